@@ -0,0 +1,293 @@
+use log::{error, info};
+
+use crate::{
+    event::{raw_config, EventSpec, DEFAULT_LDLAT, LOAD_LATENCY, PRECISE_STORE},
+    perf::PerfError,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Vendor {
+    Intel,
+    Amd,
+    Other,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Uarch {
+    SandyBridge,
+    IvyBridge,
+    Haswell,
+    Broadwell,
+    Skylake,
+    SkylakeX,
+    IceLake,
+    IceLakeX,
+    TigerLake,
+    AlderLake,
+    RaptorLake,
+    SapphireRapids,
+    EmeraldRapids,
+    GraniteRapids,
+    /// Any AMD cpu with IBS (family 10h and later, "ibs" in cpuinfo flags).
+    AmdIbs,
+}
+
+/// Family 6 model numbers, see arch/x86/include/asm/intel-family.h.
+const INTEL_MODELS: [(u32, Uarch); 35] = [
+    (0x2a, Uarch::SandyBridge),
+    (0x2d, Uarch::SandyBridge),
+    (0x3a, Uarch::IvyBridge),
+    (0x3e, Uarch::IvyBridge),
+    (0x3c, Uarch::Haswell),
+    (0x3f, Uarch::Haswell),
+    (0x45, Uarch::Haswell),
+    (0x46, Uarch::Haswell),
+    (0x3d, Uarch::Broadwell),
+    (0x47, Uarch::Broadwell),
+    (0x4f, Uarch::Broadwell),
+    (0x56, Uarch::Broadwell),
+    (0x4e, Uarch::Skylake),
+    (0x5e, Uarch::Skylake),
+    (0x8e, Uarch::Skylake),
+    (0x9e, Uarch::Skylake),
+    (0xa5, Uarch::Skylake),
+    (0xa6, Uarch::Skylake),
+    (0x55, Uarch::SkylakeX),
+    (0x7d, Uarch::IceLake),
+    (0x7e, Uarch::IceLake),
+    (0x6a, Uarch::IceLakeX),
+    (0x6c, Uarch::IceLakeX),
+    (0xa7, Uarch::IceLake),
+    (0x8c, Uarch::TigerLake),
+    (0x8d, Uarch::TigerLake),
+    (0x97, Uarch::AlderLake),
+    (0x9a, Uarch::AlderLake),
+    (0xb7, Uarch::RaptorLake),
+    (0xba, Uarch::RaptorLake),
+    (0xbf, Uarch::RaptorLake),
+    (0x8f, Uarch::SapphireRapids),
+    (0xcf, Uarch::EmeraldRapids),
+    (0xad, Uarch::GraniteRapids),
+    (0xae, Uarch::GraniteRapids),
+];
+
+/// How to sample memory accesses on a microarchitecture.
+#[derive(Debug, Clone)]
+pub enum MemEvents {
+    /// PEBS load and store events, loads lead the group.
+    Pebs { loads: EventSpec, stores: EventSpec },
+    /// AMD IBS op sampling, covers loads and stores.
+    Ibs,
+}
+
+impl Uarch {
+    /// Load latency events need the mem-loads-aux event as group leader to
+    /// record a data source, see intel_pmu_hw_config().
+    pub fn needs_mem_loads_aux(&self) -> bool {
+        matches!(
+            self,
+            Uarch::AlderLake
+                | Uarch::RaptorLake
+                | Uarch::SapphireRapids
+                | Uarch::EmeraldRapids
+                | Uarch::GraniteRapids
+        )
+    }
+
+    pub fn mem_events(&self) -> MemEvents {
+        let (loads, stores) = match self {
+            // Before Haswell only load latency and precise store record a
+            // data address.
+            Uarch::SandyBridge | Uarch::IvyBridge => {
+                let mut loads = EventSpec::raw("mem_trans_retired.load_latency", LOAD_LATENCY);
+                loads.config1 = DEFAULT_LDLAT;
+                return MemEvents::Pebs {
+                    loads,
+                    stores: EventSpec::raw("mem_trans_retired.precise_store", PRECISE_STORE),
+                };
+            }
+            Uarch::Haswell | Uarch::Broadwell => (
+                ("mem_load_uops_retired.l3_miss", raw_config(0xd1, 0x20)),
+                ("mem_uops_retired.all_stores", raw_config(0xd0, 0x82)),
+            ),
+            Uarch::AmdIbs => return MemEvents::Ibs,
+            // Skylake onwards, same for TGL and SKX.
+            _ => (
+                ("mem_load_retired.l3_miss", raw_config(0xd1, 0x20)),
+                ("mem_inst_retired.all_stores", raw_config(0xd0, 0x82)),
+            ),
+        };
+        MemEvents::Pebs {
+            loads: EventSpec::raw(loads.0, loads.1),
+            stores: EventSpec::raw(stores.0, stores.1),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CpuModel {
+    pub vendor: Vendor,
+    pub family: u32,
+    pub model: u32,
+    pub name: String,
+    pub ibs: bool,
+}
+
+impl CpuModel {
+    pub fn detect() -> Result<Self, PerfError> {
+        let cpuinfo = std::fs::read_to_string("/proc/cpuinfo").map_err(|_| {
+            error!("Failed to read /proc/cpuinfo.");
+            PerfError::Io
+        })?;
+        Self::parse(&cpuinfo).ok_or_else(|| {
+            error!("Failed to parse /proc/cpuinfo.");
+            PerfError::Parse
+        })
+    }
+
+    /// Parse the first processor entry of /proc/cpuinfo.
+    pub fn parse(cpuinfo: &str) -> Option<Self> {
+        let (mut vendor, mut family, mut model) = (None, None, None);
+        let (mut name, mut ibs) = (String::new(), false);
+        for line in cpuinfo.lines() {
+            if line.trim().is_empty() && vendor.is_some() {
+                break;
+            }
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            match key.trim() {
+                "vendor_id" => {
+                    vendor = Some(match value {
+                        "GenuineIntel" => Vendor::Intel,
+                        "AuthenticAMD" => Vendor::Amd,
+                        _ => Vendor::Other,
+                    })
+                }
+                "cpu family" => family = value.parse().ok(),
+                "model" => model = value.parse().ok(),
+                "model name" => name = value.to_string(),
+                "flags" => ibs = value.split_whitespace().any(|f| f == "ibs"),
+                _ => {}
+            }
+        }
+        Some(Self {
+            vendor: vendor?,
+            family: family?,
+            model: model?,
+            name,
+            ibs,
+        })
+    }
+
+    pub fn uarch(&self) -> Option<Uarch> {
+        match (self.vendor, self.family) {
+            (Vendor::Intel, 6) => INTEL_MODELS
+                .iter()
+                .find(|(model, _)| *model == self.model)
+                .map(|(_, uarch)| *uarch),
+            (Vendor::Amd, family) if family >= 0x10 && self.ibs => Some(Uarch::AmdIbs),
+            _ => None,
+        }
+    }
+
+    /// Memory events to sample on this cpu, refusing cpus without a
+    /// precise memory event.
+    pub fn mem_events(&self) -> Result<MemEvents, PerfError> {
+        match self.uarch() {
+            Some(uarch) => {
+                info!(
+                    "Detected {:?} (family {:#x}, model {:#x}).",
+                    uarch, self.family, self.model
+                );
+                Ok(uarch.mem_events())
+            }
+            None => {
+                error!(
+                    "No supported precise memory event for {:?} family {:#x} model {:#x} ({}). Use --event to pick one.",
+                    self.vendor, self.family, self.model, self.name
+                );
+                Err(PerfError::UnsupportedCpu)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPR: &str = "\
+processor\t: 0
+vendor_id\t: GenuineIntel
+cpu family\t: 6
+model\t\t: 143
+model name\t: Intel(R) Xeon(R) Platinum 8480+
+flags\t\t: fpu vme pebs avx512f
+
+processor\t: 1
+vendor_id\t: AuthenticAMD
+";
+
+    const ZEN: &str = "\
+processor\t: 0
+vendor_id\t: AuthenticAMD
+cpu family\t: 25
+model\t\t: 17
+model name\t: AMD EPYC 9654 96-Core Processor
+flags\t\t: fpu sse ibs skinit
+";
+
+    #[test]
+    fn parse_intel() {
+        let cpu = CpuModel::parse(SPR).unwrap();
+        assert_eq!(cpu.vendor, Vendor::Intel);
+        assert_eq!((cpu.family, cpu.model), (6, 0x8f));
+        assert_eq!(cpu.name, "Intel(R) Xeon(R) Platinum 8480+");
+        assert!(!cpu.ibs);
+        assert_eq!(cpu.uarch(), Some(Uarch::SapphireRapids));
+        assert!(Uarch::SapphireRapids.needs_mem_loads_aux());
+        assert!(matches!(
+            cpu.mem_events(),
+            Ok(MemEvents::Pebs { loads, .. }) if loads.config == raw_config(0xd1, 0x20)
+        ));
+    }
+
+    #[test]
+    fn parse_amd() {
+        let cpu = CpuModel::parse(ZEN).unwrap();
+        assert_eq!(cpu.vendor, Vendor::Amd);
+        assert_eq!((cpu.family, cpu.model), (0x19, 0x11));
+        assert!(cpu.ibs);
+        assert_eq!(cpu.uarch(), Some(Uarch::AmdIbs));
+        let no_ibs = CpuModel::parse(&ZEN.replace(" ibs", "")).unwrap();
+        assert_eq!(no_ibs.uarch(), None);
+    }
+
+    #[test]
+    fn pre_haswell_events() {
+        let ivb = CpuModel::parse(&SPR.replace("143", "58")).unwrap();
+        assert_eq!(ivb.uarch(), Some(Uarch::IvyBridge));
+        // Only these two record a data address before Haswell.
+        let Ok(MemEvents::Pebs { loads, stores }) = ivb.mem_events() else {
+            panic!("no PEBS events");
+        };
+        assert!(loads.is_load_latency());
+        assert_eq!(loads.config1, DEFAULT_LDLAT);
+        assert_eq!(stores.config, PRECISE_STORE);
+        assert!(matches!(
+            Uarch::SandyBridge.mem_events(),
+            MemEvents::Pebs { loads, .. } if loads.config == LOAD_LATENCY
+        ));
+    }
+
+    #[test]
+    fn parse_incomplete() {
+        assert!(CpuModel::parse("processor\t: 0\nvendor_id\t: GenuineIntel\n").is_none());
+        assert!(CpuModel::parse("").is_none());
+        let unknown = CpuModel::parse(&SPR.replace("143", "1")).unwrap();
+        assert_eq!(unknown.uarch(), None);
+        assert!(unknown.mem_events().is_err());
+    }
+}
@@ -0,0 +1,199 @@
+use log::error;
+
+use crate::{
+    ibs,
+    perf::{
+        perf_hw_id_PERF_COUNT_HW_BRANCH_INSTRUCTIONS, perf_hw_id_PERF_COUNT_HW_BRANCH_MISSES,
+        perf_hw_id_PERF_COUNT_HW_BUS_CYCLES, perf_hw_id_PERF_COUNT_HW_CACHE_MISSES,
+        perf_hw_id_PERF_COUNT_HW_CACHE_REFERENCES, perf_hw_id_PERF_COUNT_HW_CPU_CYCLES,
+        perf_hw_id_PERF_COUNT_HW_INSTRUCTIONS, perf_hw_id_PERF_COUNT_HW_REF_CPU_CYCLES,
+        perf_type_id_PERF_TYPE_HARDWARE, perf_type_id_PERF_TYPE_RAW, PerfError,
+    },
+    perfmon::Perfmon,
+    pmu,
+};
+
+/// Event code and umask of a core PMU event, encoded as a raw config.
+pub const fn raw_config(event: u64, umask: u64) -> u64 {
+    event | (umask << 8)
+}
+
+/// MEM_TRANS_RETIRED.LOAD_LATENCY, samples loads slower than the ldlat
+/// threshold in config1.
+pub const LOAD_LATENCY: u64 = raw_config(0xcd, 0x01);
+
+/// Load latency threshold in cycles when none is given, as perf mem uses.
+pub const DEFAULT_LDLAT: u64 = 30;
+
+/// MEM_TRANS_RETIRED.PRECISE_STORE, the only store event that records a data
+/// address before Haswell.
+pub const PRECISE_STORE: u64 = raw_config(0xcd, 0x02);
+
+/// Placeholder event that must lead a group sampling LOAD_LATENCY with a
+/// data source on Sapphire Rapids and hybrid cores.
+pub const MEM_LOADS_AUX: u64 = raw_config(0x03, 0x82);
+
+/// Memory events tracem knows by name without a perfmon file.
+const BUILTIN_EVENTS: [(&str, u64); 10] = [
+    ("mem_load_retired.l1_miss", raw_config(0xd1, 0x08)),
+    ("mem_load_retired.l2_miss", raw_config(0xd1, 0x10)),
+    ("mem_load_retired.l3_miss", raw_config(0xd1, 0x20)),
+    ("mem_load_retired.fb_hit", raw_config(0xd1, 0x40)),
+    (
+        "mem_load_l3_miss_retired.local_dram",
+        raw_config(0xd3, 0x01),
+    ),
+    (
+        "mem_load_l3_miss_retired.remote_dram",
+        raw_config(0xd3, 0x02),
+    ),
+    ("mem_inst_retired.all_loads", raw_config(0xd0, 0x81)),
+    ("mem_inst_retired.all_stores", raw_config(0xd0, 0x82)),
+    ("mem_trans_retired.load_latency", LOAD_LATENCY),
+    ("mem_trans_retired.precise_store", PRECISE_STORE),
+];
+
+const HARDWARE_EVENTS: [(&str, u32); 11] = [
+    ("cycles", perf_hw_id_PERF_COUNT_HW_CPU_CYCLES),
+    ("cpu-cycles", perf_hw_id_PERF_COUNT_HW_CPU_CYCLES),
+    ("instructions", perf_hw_id_PERF_COUNT_HW_INSTRUCTIONS),
+    (
+        "cache-references",
+        perf_hw_id_PERF_COUNT_HW_CACHE_REFERENCES,
+    ),
+    ("cache-misses", perf_hw_id_PERF_COUNT_HW_CACHE_MISSES),
+    ("branches", perf_hw_id_PERF_COUNT_HW_BRANCH_INSTRUCTIONS),
+    (
+        "branch-instructions",
+        perf_hw_id_PERF_COUNT_HW_BRANCH_INSTRUCTIONS,
+    ),
+    ("branch-misses", perf_hw_id_PERF_COUNT_HW_BRANCH_MISSES),
+    ("bus-cycles", perf_hw_id_PERF_COUNT_HW_BUS_CYCLES),
+    ("ref-cycles", perf_hw_id_PERF_COUNT_HW_REF_CPU_CYCLES),
+    ("ref-cpu-cycles", perf_hw_id_PERF_COUNT_HW_REF_CPU_CYCLES),
+];
+
+/// An event to open, resolved from an --event spec.
+#[derive(Debug, Clone)]
+pub struct EventSpec {
+    pub name: String,
+    pub type_: u32,
+    pub config: u64,
+    pub config1: u64,
+    pub config2: u64,
+    /// AMD IBS op event, sampled through its raw register payload.
+    pub ibs: bool,
+}
+
+impl EventSpec {
+    pub fn raw(name: &str, config: u64) -> Self {
+        Self {
+            name: name.to_string(),
+            type_: perf_type_id_PERF_TYPE_RAW,
+            config,
+            config1: 0,
+            config2: 0,
+            ibs: false,
+        }
+    }
+
+    pub fn is_load_latency(&self) -> bool {
+        self.type_ == perf_type_id_PERF_TYPE_RAW && self.config & 0xffff == LOAD_LATENCY
+    }
+
+    pub fn is_mem_loads_aux(&self) -> bool {
+        self.type_ == perf_type_id_PERF_TYPE_RAW && self.config == MEM_LOADS_AUX
+    }
+
+    /// Resolve an event spec. Accepted forms are `raw:0x20d1`, perf style
+    /// `r20d1`, PMU events such as `cpu/mem-loads,ldlat=30/`, generic
+    /// hardware events such as `cycles`, `ibs_op` on AMD, events defined in
+    /// the perfmon file, and the names of builtin memory events such as
+    /// `mem_load_retired.l3_miss`.
+    pub fn parse(spec: &str, perfmon: Option<&Perfmon>) -> Result<Self, PerfError> {
+        let spec = spec.trim();
+        if spec.contains('/') {
+            return pmu::parse_event(spec);
+        }
+        if let Some(hex) = spec.strip_prefix("raw:") {
+            return parse_hex(hex)
+                .map(|config| Self::raw(spec, config))
+                .ok_or_else(|| unknown(spec));
+        }
+        if let Some(config) = spec.strip_prefix('r').and_then(parse_hex) {
+            return Ok(Self::raw(spec, config));
+        }
+        let lower = spec.to_ascii_lowercase();
+        if let Some((_, config)) = HARDWARE_EVENTS.iter().find(|(n, _)| *n == lower) {
+            return Ok(Self {
+                name: lower,
+                type_: perf_type_id_PERF_TYPE_HARDWARE,
+                config: *config as u64,
+                config1: 0,
+                config2: 0,
+                ibs: false,
+            });
+        }
+        if lower == "ibs_op" {
+            return ibs::op_event();
+        }
+        if let Some(event) = perfmon.map(|p| p.resolve(spec)).transpose()?.flatten() {
+            return Ok(event);
+        }
+        if let Some((_, config)) = BUILTIN_EVENTS.iter().find(|(n, _)| *n == lower) {
+            return Ok(Self::raw(&lower, *config));
+        }
+        Err(unknown(spec))
+    }
+}
+
+fn unknown(spec: &str) -> PerfError {
+    error!("Unknown event: {}", spec);
+    PerfError::UnknownEvent
+}
+
+fn parse_hex(s: &str) -> Option<u64> {
+    let s = s.strip_prefix("0x").unwrap_or(s);
+    u64::from_str_radix(s, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_raw() {
+        for spec in ["raw:0x20d1", "raw:20d1", "r20d1", " r20D1 "] {
+            let event = EventSpec::parse(spec, None).unwrap();
+            assert_eq!(event.type_, perf_type_id_PERF_TYPE_RAW);
+            assert_eq!(event.config, 0x20d1);
+            assert!(!event.ibs);
+        }
+        assert!(EventSpec::parse("raw:xyz", None).is_err());
+        assert!(EventSpec::parse("raw:", None).is_err());
+    }
+
+    #[test]
+    fn parse_names() {
+        let cycles = EventSpec::parse("Cycles", None).unwrap();
+        assert_eq!(cycles.type_, perf_type_id_PERF_TYPE_HARDWARE);
+        assert_eq!(cycles.config, perf_hw_id_PERF_COUNT_HW_CPU_CYCLES as u64);
+        assert_eq!(cycles.name, "cycles");
+        // Not a raw event even though it starts with r.
+        let refs = EventSpec::parse("ref-cycles", None).unwrap();
+        assert_eq!(refs.type_, perf_type_id_PERF_TYPE_HARDWARE);
+
+        let l3 = EventSpec::parse("MEM_LOAD_RETIRED.L3_MISS", None).unwrap();
+        assert_eq!(l3.type_, perf_type_id_PERF_TYPE_RAW);
+        assert_eq!(l3.config, raw_config(0xd1, 0x20));
+        let ldlat = EventSpec::parse("mem_trans_retired.load_latency", None).unwrap();
+        assert!(ldlat.is_load_latency());
+        assert!(!l3.is_load_latency());
+        assert!(EventSpec::raw("aux", MEM_LOADS_AUX).is_mem_loads_aux());
+
+        assert!(matches!(
+            EventSpec::parse("no_such_event", None),
+            Err(PerfError::UnknownEvent)
+        ));
+    }
+}
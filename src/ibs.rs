@@ -0,0 +1,198 @@
+use crate::{
+    event::EventSpec,
+    perf::{MemSource, PerfError},
+    pmu::Pmu,
+};
+
+// IbsOpData3 bits.
+const IBS_LD_OP: u64 = 1 << 0;
+const IBS_ST_OP: u64 = 1 << 1;
+const IBS_DC_MISS: u64 = 1 << 7;
+const IBS_DC_LIN_ADDR_VALID: u64 = 1 << 17;
+const IBS_DC_PHY_ADDR_VALID: u64 = 1 << 18;
+const IBS_L2_MISS: u64 = 1 << 20;
+
+// IbsOpData2 DataSrc values, the extended encoding is Zen 4 and later.
+const IBS_DATA_SRC_LOC_CACHE: u8 = 2;
+const IBS_DATA_SRC_DRAM: u8 = 3;
+const IBS_DATA_SRC_REM_CACHE: u8 = 4;
+const IBS_DATA_SRC_EXT_LOC_CACHE: u8 = 1;
+const IBS_DATA_SRC_EXT_FAR_CCX_CACHE: u8 = 5;
+const IBS_DATA_SRC_EXT_PMEM: u8 = 6;
+const IBS_DATA_SRC_IO: u8 = 7;
+const IBS_DATA_SRC_EXT_EXT_MEM: u8 = 8;
+const IBS_DATA_SRC_EXT_PEER_AGENT_MEM: u8 = 12;
+
+/// The IBS op registers captured in a PERF_SAMPLE_RAW payload.
+#[derive(Debug, Clone, Copy)]
+pub struct IbsOp {
+    pub rip: u64,
+    pub data: u64,
+    pub data2: u64,
+    pub data3: u64,
+    pub dc_lin_addr: u64,
+    pub dc_phys_addr: u64,
+}
+
+impl IbsOp {
+    /// Decode a PERF_SAMPLE_RAW payload from the ibs_op PMU: a u32 of IBS
+    /// capabilities followed by IbsOpCtl, IbsOpRip, IbsOpData, IbsOpData2,
+    /// IbsOpData3, IbsDcLinAd and IbsDcPhysAd (and possibly more).
+    pub fn decode(raw: &[u8]) -> Option<Self> {
+        let reg = |i: usize| -> Option<u64> {
+            let off = 4 + i * 8;
+            Some(u64::from_ne_bytes(raw.get(off..off + 8)?.try_into().ok()?))
+        };
+        Some(Self {
+            rip: reg(1)?,
+            data: reg(2)?,
+            data2: reg(3)?,
+            data3: reg(4)?,
+            dc_lin_addr: reg(5)?,
+            dc_phys_addr: reg(6)?,
+        })
+    }
+
+    pub fn is_load(&self) -> bool {
+        self.data3 & IBS_LD_OP != 0
+    }
+
+    pub fn is_store(&self) -> bool {
+        self.data3 & IBS_ST_OP != 0
+    }
+
+    /// Data linear address, if the op accessed memory and the address is valid.
+    pub fn lin_addr(&self) -> Option<u64> {
+        (self.data3 & IBS_DC_LIN_ADDR_VALID != 0).then_some(self.dc_lin_addr)
+    }
+
+    pub fn phys_addr(&self) -> Option<u64> {
+        (self.data3 & IBS_DC_PHY_ADDR_VALID != 0).then_some(self.dc_phys_addr)
+    }
+
+    /// Cycles from the data cache miss to the fill.
+    pub fn dc_miss_latency(&self) -> u64 {
+        (self.data3 >> 32) & 0xffff
+    }
+
+    /// The load was served from another node.
+    pub fn remote_node(&self) -> bool {
+        self.data2 & (1 << 4) != 0
+    }
+
+    fn data_src(&self) -> u8 {
+        (((self.data2 >> 6) & 0x3) << 3 | (self.data2 & 0x7)) as u8
+    }
+
+    /// Where the access was served from. Only loads that missed the data
+    /// cache carry a data source, other misses are reported as Unknown.
+    pub fn source(&self) -> MemSource {
+        if self.data3 & IBS_DC_MISS == 0 {
+            return MemSource::L1;
+        }
+        if !self.is_load() {
+            return MemSource::Unknown;
+        }
+        match self.data_src() {
+            0 if self.data3 & IBS_L2_MISS == 0 => MemSource::L2,
+            IBS_DATA_SRC_EXT_LOC_CACHE | IBS_DATA_SRC_LOC_CACHE => MemSource::L3,
+            IBS_DATA_SRC_REM_CACHE | IBS_DATA_SRC_EXT_FAR_CCX_CACHE => MemSource::RemoteCache,
+            IBS_DATA_SRC_DRAM if self.remote_node() => MemSource::RemoteDram,
+            IBS_DATA_SRC_DRAM => MemSource::LocalDram,
+            IBS_DATA_SRC_EXT_PMEM => MemSource::Pmem,
+            IBS_DATA_SRC_IO => MemSource::Io,
+            IBS_DATA_SRC_EXT_EXT_MEM | IBS_DATA_SRC_EXT_PEER_AGENT_MEM => MemSource::Cxl,
+            _ => MemSource::Unknown,
+        }
+    }
+}
+
+/// The ibs_op PMU event, counting ops rather than cycles when the PMU
+/// supports cnt_ctl.
+pub fn op_event() -> Result<EventSpec, PerfError> {
+    let pmu = Pmu::load("ibs_op")?;
+    let terms = if pmu.formats.contains_key("cnt_ctl") {
+        "cnt_ctl=1"
+    } else {
+        ""
+    };
+    pmu.event(terms)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A PERF_SAMPLE_RAW payload: the caps u32, then IbsOpCtl, IbsOpRip,
+    // IbsOpData, IbsOpData2, IbsOpData3, IbsDcLinAd and IbsDcPhysAd.
+    fn raw(data2: u64, data3: u64) -> Vec<u8> {
+        let mut raw = 0x1ffu32.to_ne_bytes().to_vec();
+        for reg in [0, 0x401000, 0, data2, data3, 0x7f0000001234, 0x12345678] {
+            raw.extend_from_slice(&u64::to_ne_bytes(reg));
+        }
+        raw
+    }
+
+    // IbsOpData2 with its split DataSrc field and the remote node bit.
+    fn data2(src: u8, remote: bool) -> u64 {
+        (src as u64 & 0x7) | (src as u64 >> 3) << 6 | (remote as u64) << 4
+    }
+
+    #[test]
+    fn decode() {
+        let valid = IBS_LD_OP | IBS_DC_LIN_ADDR_VALID | IBS_DC_PHY_ADDR_VALID;
+        let op = IbsOp::decode(&raw(0, valid | 250 << 32)).unwrap();
+        assert_eq!(op.rip, 0x401000);
+        assert!(op.is_load() && !op.is_store());
+        assert_eq!(op.lin_addr(), Some(0x7f0000001234));
+        assert_eq!(op.phys_addr(), Some(0x12345678));
+        assert_eq!(op.dc_miss_latency(), 250);
+
+        // Addresses without their valid bit are not used.
+        let op = IbsOp::decode(&raw(0, IBS_ST_OP)).unwrap();
+        assert!(op.is_store() && !op.is_load());
+        assert_eq!(op.lin_addr(), None);
+        assert_eq!(op.phys_addr(), None);
+
+        // Payloads cut short before IbsDcPhysAd.
+        let raw = raw(0, valid);
+        assert!(IbsOp::decode(&raw[..raw.len() - 1]).is_none());
+        assert!(IbsOp::decode(&[]).is_none());
+    }
+
+    #[test]
+    fn source() {
+        let source = |data2, data3| IbsOp::decode(&raw(data2, data3)).unwrap().source();
+        let miss = IBS_LD_OP | IBS_DC_MISS;
+        assert_eq!(
+            source(data2(IBS_DATA_SRC_DRAM, false), IBS_LD_OP),
+            MemSource::L1
+        );
+        assert_eq!(source(0, IBS_ST_OP | IBS_DC_MISS), MemSource::Unknown);
+        // No data source, served from L2 unless it missed there too.
+        assert_eq!(source(0, miss), MemSource::L2);
+        assert_eq!(source(0, miss | IBS_L2_MISS), MemSource::Unknown);
+        let l2_miss = miss | IBS_L2_MISS;
+        let cases = [
+            (IBS_DATA_SRC_LOC_CACHE, false, MemSource::L3),
+            (IBS_DATA_SRC_REM_CACHE, false, MemSource::RemoteCache),
+            (IBS_DATA_SRC_DRAM, false, MemSource::LocalDram),
+            (IBS_DATA_SRC_DRAM, true, MemSource::RemoteDram),
+            (IBS_DATA_SRC_IO, false, MemSource::Io),
+            // The extended encoding of Zen 4, with the high DataSrc bits.
+            (IBS_DATA_SRC_EXT_LOC_CACHE, false, MemSource::L3),
+            (
+                IBS_DATA_SRC_EXT_FAR_CCX_CACHE,
+                false,
+                MemSource::RemoteCache,
+            ),
+            (IBS_DATA_SRC_EXT_PMEM, false, MemSource::Pmem),
+            (IBS_DATA_SRC_EXT_EXT_MEM, false, MemSource::Cxl),
+            (IBS_DATA_SRC_EXT_PEER_AGENT_MEM, true, MemSource::Cxl),
+            (31, false, MemSource::Unknown),
+        ];
+        for (src, remote, expected) in cases {
+            assert_eq!(source(data2(src, remote), l2_miss), expected, "{}", src);
+        }
+    }
+}
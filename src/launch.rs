@@ -0,0 +1,211 @@
+use std::{
+    ffi::CString,
+    fs::File,
+    io::{self, Write},
+    os::fd::{AsRawFd, FromRawFd},
+};
+
+use log::{debug, error, info, warn};
+
+use crate::signal::SignalFd;
+
+// How often wait_signaled checks whether the workload exited.
+const WAIT_POLL_MS: i32 = 100;
+
+/// A workload forked by tracem. The child blocks before exec until start()
+/// is called, so events can be opened on its pid (with enable_on_exec) and
+/// nothing of its startup is missed. Dropping an unstarted workload makes the
+/// child exit without running the command.
+pub struct Workload {
+    pid: i32,
+    go: Option<File>,
+    status: Option<i32>,
+    // A signal was sent to the workload, see wait_signaled.
+    signaled: bool,
+}
+
+impl Workload {
+    pub fn spawn(cmd: &[String]) -> io::Result<Self> {
+        if cmd.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "empty command"));
+        }
+        // Everything the child needs is allocated before fork, after fork the
+        // child only makes async-signal-safe calls.
+        let args = cmd
+            .iter()
+            .map(|a| CString::new(a.as_bytes()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let mut argv = args.iter().map(|a| a.as_ptr()).collect::<Vec<_>>();
+        argv.push(std::ptr::null());
+
+        let mut fds = [0; 2];
+        // SAFETY: fds has room for the two pipe ends.
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let [read_fd, write_fd] = fds;
+        // SAFETY: The child only calls async-signal-safe functions before exec.
+        match unsafe { libc::fork() } {
+            -1 => {
+                let err = io::Error::last_os_error();
+                // SAFETY: Both fds were just opened by pipe2 and are owned here.
+                unsafe {
+                    libc::close(read_fd);
+                    libc::close(write_fd);
+                }
+                Err(err)
+            }
+            0 => unsafe {
+                // SAFETY: Child. Wait for the parent to release us, if the
+                // write end closes without a byte being written tracem failed
+                // to set up and we must not run the workload.
+                libc::close(write_fd);
+                let mut go = 0u8;
+                let n = libc::read(read_fd, (&mut go as *mut u8).cast(), 1);
+                if n != 1 {
+                    libc::_exit(1);
+                }
+                libc::execvp(argv[0], argv.as_ptr());
+                libc::_exit(127);
+            },
+            pid => {
+                // SAFETY: Parent. read_fd belongs to the child, write_fd is
+                // owned by the returned File.
+                unsafe { libc::close(read_fd) };
+                let go = unsafe { File::from_raw_fd(write_fd) };
+                debug!("Forked workload {} for {:?}.", pid, cmd);
+                Ok(Self {
+                    pid,
+                    go: Some(go),
+                    status: None,
+                    signaled: false,
+                })
+            }
+        }
+    }
+
+    pub fn pid(&self) -> i32 {
+        self.pid
+    }
+
+    /// Let the child exec the workload.
+    pub fn start(&mut self) -> io::Result<()> {
+        if let Some(mut go) = self.go.take() {
+            go.write_all(&[1])?;
+            info!("Started workload {}.", self.pid);
+        }
+        Ok(())
+    }
+
+    /// Send sig to the workload unless it was already reaped.
+    pub fn kill(&mut self, sig: i32) -> io::Result<()> {
+        if self.status.is_some() {
+            return Ok(());
+        }
+        // SAFETY: pid is our unreaped child so it can't have been recycled.
+        if unsafe { libc::kill(self.pid, sig) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.signaled = true;
+        debug!("Sent signal {} to workload {}.", sig, self.pid);
+        Ok(())
+    }
+
+    /// Reap the child, blocking until it exits. Returns its wait status.
+    pub fn wait(&mut self) -> io::Result<i32> {
+        loop {
+            if let Some(status) = self.reap(0)? {
+                return Ok(status);
+            }
+        }
+    }
+
+    /// Reap the child like wait, while handling the signals of signals. The
+    /// first one is passed on to the workload, any signal after the workload
+    /// was sent one kills it, so a workload that ignores SIGTERM cannot keep
+    /// tracem from exiting.
+    pub fn wait_signaled(&mut self, signals: &SignalFd) -> io::Result<i32> {
+        loop {
+            if let Some(status) = self.reap(libc::WNOHANG)? {
+                return Ok(status);
+            }
+            let mut fd = libc::pollfd {
+                fd: signals.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            // SAFETY: fd is a single valid pollfd.
+            if unsafe { libc::poll(&mut fd, 1, WAIT_POLL_MS) } < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::Interrupted {
+                    return Err(err);
+                }
+            }
+            while let Some(sig) = signals.read()? {
+                if self.signaled {
+                    warn!(
+                        "Received signal {} again, killing workload {}.",
+                        sig, self.pid
+                    );
+                    self.kill(libc::SIGKILL)?;
+                } else {
+                    info!(
+                        "Received signal {}, passing it on to workload {}.",
+                        sig, self.pid
+                    );
+                    self.kill(sig)?;
+                }
+            }
+        }
+    }
+
+    // waitpid with flags, None while the child is still running with
+    // WNOHANG.
+    fn reap(&mut self, flags: i32) -> io::Result<Option<i32>> {
+        // Closing the pipe stops an unstarted child from exec'ing.
+        self.go.take();
+        if let Some(status) = self.status {
+            return Ok(Some(status));
+        }
+        let mut status = 0;
+        // SAFETY: pid is our child and status is a valid out pointer.
+        match unsafe { libc::waitpid(self.pid, &mut status, flags) } {
+            0 => return Ok(None),
+            ret if ret == self.pid => {}
+            _ => {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    return Ok(None);
+                }
+                error!("Failed to wait for workload {}.", self.pid);
+                return Err(err);
+            }
+        }
+        if libc::WIFEXITED(status) {
+            info!(
+                "Workload {} exited with status {}.",
+                self.pid,
+                libc::WEXITSTATUS(status)
+            );
+        } else if libc::WIFSIGNALED(status) {
+            info!(
+                "Workload {} killed by signal {}.",
+                self.pid,
+                libc::WTERMSIG(status)
+            );
+        }
+        self.status = Some(status);
+        Ok(Some(status))
+    }
+}
+
+impl Drop for Workload {
+    fn drop(&mut self) {
+        // An unstarted child exits as soon as the pipe closes, reap it. A
+        // running workload is left alone.
+        if self.go.is_some() {
+            let _ = self.wait();
+        }
+    }
+}
@@ -4,52 +4,92 @@
 #![allow(dead_code)] // For bindings
 
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     fs::File,
-    mem::size_of,
+    os::fd::AsRawFd,
+    path::PathBuf,
     sync::{
-        atomic::{AtomicU8, Ordering},
+        atomic::{AtomicU64, Ordering},
         Arc, RwLock,
     },
-    thread::{self, JoinHandle},
+    time::Duration,
 };
 
-use clap::Parser;
-use log::{debug, error, info};
+use clap::{error::ErrorKind, CommandFactory, Parser, ValueEnum};
+use cpu::{CpuModel, MemEvents};
+use event::{EventSpec, LOAD_LATENCY, MEM_LOADS_AUX};
+use ibs::IbsOp;
+use launch::Workload;
+use log::{debug, error, info, warn};
 use perf::{
     perf_event_sample_format_PERF_SAMPLE_IDENTIFIER,
     perf_sys::{
-        perf_event_attr, perf_event_header, perf_event_sample_format_PERF_SAMPLE_ADDR,
+        perf_event_attr, perf_event_sample_format_PERF_SAMPLE_ADDR,
+        perf_event_sample_format_PERF_SAMPLE_DATA_PAGE_SIZE,
+        perf_event_sample_format_PERF_SAMPLE_DATA_SRC,
+        perf_event_sample_format_PERF_SAMPLE_PHYS_ADDR, perf_event_sample_format_PERF_SAMPLE_RAW,
         perf_event_sample_format_PERF_SAMPLE_TID, perf_event_sample_format_PERF_SAMPLE_TIME,
-        perf_type_id_PERF_TYPE_RAW,
+        perf_event_sample_format_PERF_SAMPLE_WEIGHT, PERF_FLAG_PID_CGROUP,
+        PERF_RECORD_MISC_CPUMODE_MASK, PERF_RECORD_MISC_USER,
     },
     PerfError,
 };
+use perfmon::Perfmon;
+use policy::{
+    Aging, AgingMode, DryRun, LruPolicy, MigrationPolicy, PageMap, Policy, PolicyKind, RateLimit,
+    RatioPolicy, ThresholdPolicy, TopKPolicy, WatermarkPolicy, Watermarks,
+};
+use signal::SignalFd;
+use smaps::PageSizes;
+use topology::{MemoryBlocks, TierTopology};
 
-use crate::perf::{perf_event_sample_format_PERF_SAMPLE_IP, PerfEvent};
+use crate::perf::{
+    perf_event_sample_format_PERF_SAMPLE_IP, Comm, Lost, MemSource, Mmap2, PerfEvent, PerfEventSet,
+    Sample, StopReason, Task, Throttle,
+};
 
 mod numa_sys {
     include!(concat!(env!("OUT_DIR"), "/numa-sys.rs"));
 }
 
+mod cpu;
+mod event;
+mod ibs;
+mod launch;
 mod perf;
+mod perfmon;
+mod pmu;
+mod policy;
+mod signal;
+mod smaps;
+mod topology;
 
 type PageT = u64;
 type CostT = u64;
 type NodeT = (u32, i32); // Node id, (success/failed) migration count
+type SourceCounts = [u64; MemSource::COUNT];
 
 const SAMPLE_FREQ: u64 = 4000;
 const PAGE_SIZE: u64 = 4096;
+// Node of a page whose placement has not been queried yet.
+const UNKNOWN_NODE: u32 = u32::MAX;
+// Pages per move_pages call.
+const MOVE_PAGES_BATCH: usize = 1024;
+// Nanoseconds of trace time between rereads of the target's smaps after it
+// mapped something new.
+const SMAPS_PERIOD: u64 = 100_000_000;
 
-// Should be same for TGL and SKX
-const L3MISS: u64 = 0xd1 | (0x20 << 8);
-const ALLSTORES: u64 = 0xd0 | (0x82 << 8);
-
-const pol_flag_wait: u8 = 0;
-const pol_flag_run: u8 = 1;
-const pol_flag_stop: u8 = 2;
+/// What a sample adds to the cost of its page.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum CostMode {
+    /// Every sample counts as 1.
+    Samples,
+    /// The sampled access latency in cycles, from the sample weight or the
+    /// IBS data cache miss latency.
+    Latency,
+}
 
-#[derive(Parser)]
+#[derive(Parser, Clone)]
 struct Args {
     #[clap(short, long, default_value = "0")]
     pid: i32,
@@ -57,174 +97,268 @@ struct Args {
     cpu: i32,
     #[clap(short, long, default_value = "1000")]
     sample_period: u64,
+    /// Open one event group per online CPU. Implied by --pid -1 and --cgroup.
+    #[clap(long)]
+    per_cpu: bool,
+    /// Trace every task in this cgroup (path under the cgroup mount).
+    #[clap(long)]
+    cgroup: Option<PathBuf>,
+    /// Follow every thread of --pid, including threads spawned after attach.
+    /// Each thread gets its own event group, --per-cpu is ignored.
+    #[clap(long)]
+    threads: bool,
+    /// Ring buffer size of each event group in pages, a power of two. Every
+    /// cpu or thread gets its own group and ring.
+    #[clap(long, default_value = "128")]
+    mmap_pages: usize,
+    /// Event to sample, may be repeated. The first event leads the group.
+    /// Defaults to the load miss and store events for the detected cpu.
+    /// Accepts raw:0x20d1, r20d1, PMU events like cpu/mem-loads,ldlat=30/,
+    /// hardware events like cycles, ibs_op, known memory events like
+    /// mem_load_retired.l3_miss or any PEBS event in --perfmon.
+    #[clap(short, long = "event")]
+    events: Vec<String>,
+    /// Load latency threshold in cycles. Loads are sampled with
+    /// mem_trans_retired.load_latency and the threshold overrides the one of
+    /// any load latency --event.
+    #[clap(long)]
+    ldlat: Option<u64>,
+    /// What a sample adds to the cost of its page.
+    #[clap(long, value_enum, default_value = "samples")]
+    cost: CostMode,
+    /// Tier file, one line per tier from fastest to slowest listing its
+    /// nodes (e.g. 0-1). Defaults to the kernel memory tiers.
+    #[clap(long)]
+    tiers: Option<PathBuf>,
+    /// Migration strategy.
+    #[clap(long, value_enum, default_value = "watermark")]
+    policy: PolicyKind,
+    /// Threshold policy: promote pages with at least this cost.
+    #[clap(long, default_value = "8")]
+    hot_threshold: u64,
+    /// Threshold policy: demote pages with less than this cost.
+    #[clap(long, default_value = "1")]
+    cold_threshold: u64,
+    /// LRU policy: policy rounds without a sample before a page is cold.
+    #[clap(long, default_value = "4")]
+    lru_rounds: u32,
+    /// Migrate at most this many MB per second.
+    #[clap(long)]
+    migrate_mbps: Option<u64>,
+    /// Migrate at most this many pages per policy round.
+    #[clap(long)]
+    migrate_pages: Option<u64>,
+    /// Decide migrations without making them and report what would have
+    /// moved when tracing stops. move_pages is never called, not even to
+    /// query placement, so pages are only placed with --phys-addr.
+    #[clap(long)]
+    dry_run: bool,
+    /// Write every dry run decision to this CSV file.
+    #[clap(long, requires = "dry_run")]
+    decision_log: Option<PathBuf>,
+    /// Demote cold pages once a fast tier has less than this percent of its
+    /// memory free.
+    #[clap(long, default_value = "5", value_parser = clap::value_parser!(u64).range(0..=100))]
+    low_watermark: u64,
+    /// Promote hot pages while a fast tier has more than this percent of its
+    /// memory free, and demote down to it. Raised to --low-watermark if lower.
+    #[clap(long, default_value = "10", value_parser = clap::value_parser!(u64).range(0..=100))]
+    high_watermark: u64,
+    /// How page costs decay over time. Not with --policy lru.
+    #[clap(long, value_enum, default_value = "none")]
+    aging: AgingMode,
+    /// Aging period in milliseconds: the halving period, EWMA time constant
+    /// or window length.
+    #[clap(long, default_value = "1000")]
+    aging_period: u64,
+    /// Milliseconds between policy rounds.
+    #[clap(long, default_value = "500", value_parser = clap::value_parser!(u64).range(1..))]
+    interval: u64,
+    /// Sample physical addresses and take the node of each page from the
+    /// memory block it lives in. Needs CAP_SYS_ADMIN or CAP_PERFMON.
+    #[clap(long)]
+    phys_addr: bool,
+    /// intel/perfmon event JSON file used to resolve symbolic event names.
+    #[clap(long)]
+    perfmon: Option<PathBuf>,
+    /// Stop tracing after this many seconds. A launched workload is sent
+    /// SIGTERM.
+    #[clap(short, long)]
+    duration: Option<u64>,
+    /// Launch and trace this command instead of attaching to --pid.
+    #[clap(last = true)]
+    command: Vec<String>,
 }
 
-#[derive(Default, Debug)]
-#[repr(C)]
-struct demo_record {
-    hdr: perf_event_header,
-    id: u64,
-    ip: u64,
-    tid: u64,
-    time: u64,
-    addr: u64,
+/// What an event group is attached to, see perf_event_open(2) pid/cpu/flags.
+#[derive(Clone, Copy)]
+struct Target {
+    pid: i32,
+    cpu: i32,
+    flags: i32,
 }
 
 trait Tracker {
-    fn update(&mut self, addr: u64);
-    fn execute(&self);
+    fn update(&mut self, page: PageT, size: u64, cost: CostT);
     fn debug_summary(&self);
-    fn handle_sample(&mut self, sample: *const u8);
+    fn handle_sample(&mut self, sample: &Sample);
+    fn handle_lost(&mut self, _lost: &Lost) {}
+    fn handle_throttle(&mut self, _throttle: &Throttle, _throttled: bool) {}
+    fn handle_mmap(&mut self, _mmap: &Mmap2) {}
+    fn handle_comm(&mut self, _comm: &Comm) {}
+    fn handle_fork(&mut self, _task: &Task) {}
+    fn handle_exit(&mut self, _task: &Task) {}
+    // Checked after each ring drain, the sample loop returns once true.
+    fn finished(&self) -> bool {
+        false
+    }
 }
 
-struct Policy {
-    handle: Option<JoinHandle<()>>,
-    tiers: Vec<Vec<(PageT, CostT)>>,
+struct PolTracker {
+    pol_thread: Policy,
+    pid: i32,
+    cost_mode: CostMode,
+    topology: Arc<TierTopology>,
+    inner: Arc<RwLock<PageMap>>,
+    // Time of the latest sample, drives aging on the policy thread.
+    clock: Arc<AtomicU64>,
+    // Per page count of samples by where the access was served from.
+    sources: HashMap<PageT, SourceCounts>,
+    // Newly tracked pages waiting for their placement to be queried.
+    unplaced: Vec<PageT>,
+    // Placement queries use move_pages too, a dry run leaves them out.
+    query_placement: bool,
+    blocks: Option<MemoryBlocks>,
+    // Page sizes of the target's mappings and the sample time they were
+    // read at, see refresh_page_sizes.
+    page_sizes: PageSizes,
+    page_sizes_stale: bool,
+    page_sizes_time: Option<u64>,
+    maps: BTreeMap<u64, Mmap2>,
+    lost: u64,
+    throttled: u64,
+    exited: bool,
 }
 
-impl Policy {
-    fn new() -> Self {
+impl PolTracker {
+    fn new(
+        pid: i32,
+        cost_mode: CostMode,
+        topology: Arc<TierTopology>,
+        policy: Policy,
+        blocks: Option<MemoryBlocks>,
+        query_placement: bool,
+    ) -> Self {
         Self {
-            handle: None,
-            tiers: vec![vec![], vec![]],
+            pol_thread: policy,
+            pid,
+            cost_mode,
+            topology,
+            inner: Arc::new(RwLock::new(HashMap::new())),
+            clock: Arc::new(AtomicU64::new(0)),
+            sources: HashMap::new(),
+            unplaced: Vec::new(),
+            query_placement,
+            blocks,
+            page_sizes: PageSizes::default(),
+            page_sizes_stale: true,
+            page_sizes_time: None,
+            maps: BTreeMap::new(),
+            lost: 0,
+            throttled: 0,
+            exited: false,
         }
     }
 
-    fn start(
-        &mut self,
-        target_pid: i32,
-        pol_flag: Arc<AtomicU8>,
-        tracking: Arc<RwLock<HashMap<PageT, (CostT, NodeT)>>>,
-    ) {
-        if let None = self.handle {
-            self.handle = Some(thread::spawn(move || loop {
-                let tracking_clone = tracking.clone();
-                match pol_flag.load(Ordering::Relaxed) {
-                    pol_flag_wait => {
-                        thread::park();
-                    }
-                    pol_flag_run => {
-                        Self::execute(target_pid, tracking_clone);
-                        pol_flag.store(pol_flag_wait, Ordering::Relaxed);
-                    }
-                    pol_flag_stop => {
-                        break;
-                    }
-                    _ => {
-                        error!("Invalid policy flag.");
-                        break;
-                    }
-                }
-            }))
-        }
+    fn start_policy(&mut self) {
+        self.pol_thread
+            .start(self.pid, self.inner.clone(), self.clock.clone());
+        debug!("Started policy thread.");
     }
 
-    fn notify(&self) {
-        if let Some(handle) = self.handle.as_ref() {
-            handle.thread().unpark();
+    // Record the node a page was observed on, taken from its physical address.
+    fn place(&mut self, page: PageT, phys_addr: u64) {
+        let Some(node) = self.blocks.as_ref().and_then(|b| b.node_of(phys_addr)) else {
+            return;
+        };
+        if let Some((_, (n, _), _)) = self.inner.write().unwrap().get_mut(&page) {
+            *n = node;
         }
     }
 
-    fn join(&mut self) {
-        if let Some(handle) = self.handle.take() {
-            handle.thread().unpark();
-            handle.join().unwrap();
+    // Reread smaps once the target mapped something new, at most every
+    // SMAPS_PERIOD, for the hugetlbfs mappings of samples that come without
+    // a page size. System wide tracing has no single target and stays at
+    // base pages.
+    fn refresh_page_sizes(&mut self, time: Option<u64>) {
+        if self.pid <= 0 || !self.page_sizes_stale {
+            return;
+        }
+        let since = match (time, self.page_sizes_time) {
+            (_, None) => u64::MAX,
+            (Some(now), Some(last)) => now.saturating_sub(last),
+            (None, Some(_)) => 0,
+        };
+        if since < SMAPS_PERIOD {
+            return;
         }
+        // Keep the old sizes if the target is already gone.
+        if let Ok(sizes) = PageSizes::load(self.pid) {
+            self.page_sizes = sizes;
+        }
+        self.page_sizes_stale = false;
+        self.page_sizes_time = Some(time.unwrap_or(0));
     }
 
-    fn execute(target_pid: i32, tracking: Arc<RwLock<HashMap<PageT, (CostT, NodeT)>>>) {
-        // Update tiers lists
-        let entries = tracking.read().unwrap();
-        let fast_tier_len = entries
-            .iter()
-            .filter(|(_, (_, node))| (*node).0 == 0)
-            .count();
-        let slow_tier_len = entries
-            .iter()
-            .filter(|(_, (_, node))| (*node).0 == 1)
-            .count();
-        // * Threshold conditions check*
-        // Maintain ratio of 4:1 for tier 0 to tier 1
-        // If fast tier is 4 times larger than slow tier, migrate pages from fast to slow
-        // If slow tier is more than 1/4th of fast tier, migrate pages from slow to fast
-        let (target_node, ratio) = match (fast_tier_len, slow_tier_len) {
-            (fast_len, slow_len) if fast_len > 4 * slow_len => (1, fast_len / (4 * (1 + slow_len))),
-            (fast_len, slow_len) if slow_len > fast_len / 2 => (0, slow_len / ((1 + fast_len) / 2)),
-            _ => return,
-        };
-        let mut candidates = entries
-            .iter()
-            .filter(|(_, (_, node))| (*node).0 == 1 - target_node && (*node).1 > -10)
-            .map(|(addr, _)| *addr)
-            .take(ratio)
-            .collect::<Vec<_>>();
-        drop(entries);
-        let n = candidates.len();
-        let nodes: Vec<i32> = vec![target_node as i32; n];
-        let mut status = vec![-123; n];
-        let ret = unsafe {
-            numa_sys::move_pages(
-                target_pid,
-                n as u64,
-                candidates.as_mut_ptr().cast(),
-                nodes.as_ptr(),
-                status.as_mut_ptr(),
-                0,
-            )
-        };
-        if ret < 0 {
-            error!("Failed to move pages.");
-        } else {
-            let mut entries = tracking.write().unwrap();
-            for (p, s) in candidates.iter().zip(status.iter()) {
-                if let Some((_, node)) = entries.get_mut(p) {
-                    // Check that status is non-negative
-                    if *s < 0 {
-                        (*node).1 -= 1;
-                    } else {
-                        (*node).0 = *s as u32;
-                    }
-                }
+    // Merge the smaller pages tracked inside a huge page into it, so the base
+    // pages of a collapsed huge page add up to one entry instead of being
+    // counted and migrated one by one.
+    fn fold_pages(&mut self, page: PageT, size: u64) {
+        let mut entries = self.inner.write().unwrap();
+        let mut folded = 0;
+        for addr in (page + PAGE_SIZE..page + size).step_by(PAGE_SIZE as usize) {
+            let Some((cost, _, _)) = entries.remove(&addr) else {
+                continue;
+            };
+            if let Some(entry) = entries.get_mut(&page) {
+                entry.0 += cost;
             }
-            if n > 0 {
-                debug!(
-                    "Moved {} pages from tier {} to tier {}.",
-                    n,
-                    1 - target_node,
-                    target_node
-                );
+            if let Some(counts) = self.sources.remove(&addr) {
+                let total = self.sources.entry(page).or_insert([0; MemSource::COUNT]);
+                total.iter_mut().zip(counts).for_each(|(t, c)| *t += c);
             }
+            folded += 1;
+        }
+        if folded > 0 {
+            debug!("Folded {} pages into the huge page at {:#x}.", folded, page);
         }
     }
-}
-
-struct PolTracker {
-    pol_flag: Arc<AtomicU8>,
-    pol_thread: Policy,
-    pid: i32,
-    inner: Arc<RwLock<HashMap<PageT, (CostT, NodeT)>>>,
-}
 
-impl PolTracker {
-    fn new(pid: i32) -> Self {
-        Self {
-            pol_flag: Arc::new(AtomicU8::new(0)),
-            pol_thread: Policy::new(),
-            pid,
-            inner: Arc::new(RwLock::new(HashMap::new())),
-        }
+    fn main_source(&self, page: PageT) -> MemSource {
+        self.sources
+            .get(&page)
+            .and_then(|counts| {
+                MemSource::ALL
+                    .into_iter()
+                    .zip(counts)
+                    .filter(|(_, n)| **n > 0)
+                    .max_by_key(|(_, n)| **n)
+            })
+            .map_or(MemSource::Unknown, |(source, _)| source)
     }
 
-    fn start_policy(&mut self) {
-        self.pol_thread
-            .start(self.pid, self.pol_flag.clone(), self.inner.clone());
-        debug!("Started policy thread.");
+    // Stores and cache hits often carry no latency, they still count as 1 so
+    // the page is tracked.
+    fn cost(&self, latency: u64) -> CostT {
+        match self.cost_mode {
+            CostMode::Samples => 1,
+            CostMode::Latency => latency.max(1),
+        }
     }
 }
 
 impl Drop for PolTracker {
     fn drop(&mut self) {
-        self.pol_flag.store(pol_flag_stop, Ordering::Relaxed);
         self.pol_thread.join();
         debug!("Stopped policy thread.");
     }
@@ -232,45 +366,75 @@ impl Drop for PolTracker {
 
 impl Tracker for PolTracker {
     // Update cost associated with a page. Assumes addr is page aligned.
-    fn update(&mut self, page: PageT) {
+    fn update(&mut self, page: PageT, size: u64, cost: CostT) {
         //let entry = self.inner.entry(page).or_insert(0);
+        let mut grown = false;
         if let Ok(mut inner) = self.inner.write() {
-            let entry = inner.entry(page).or_insert((0, (0, 0)));
-            (*entry).0 += 1;
+            let entry = inner.entry(page).or_insert_with(|| {
+                self.unplaced.push(page);
+                (0, (UNKNOWN_NODE, 0), PAGE_SIZE)
+            });
+            grown = entry.2 < size;
+            entry.0 += cost;
+            entry.2 = size;
         } else {
             error!("Failed to update page cost.");
         }
-    }
-
-    fn execute(&self) {
-        self.pol_flag.store(pol_flag_run, Ordering::Relaxed);
-        self.pol_thread.notify();
+        if grown {
+            self.fold_pages(page, size);
+        }
+        if self.unplaced.len() >= MOVE_PAGES_BATCH {
+            let pages = std::mem::take(&mut self.unplaced);
+            if self.query_placement {
+                Policy::refresh(self.pid, &self.inner, &pages);
+            }
+        }
     }
 
     fn debug_summary(&self) {
+        info!("Lost records: {}", self.lost);
+        info!("Throttle events: {}", self.throttled);
+        info!("Known mappings: {}", self.maps.len());
         let entries = self.inner.read().unwrap();
         info!("Total pages: {}", entries.len());
-        info!(
-            "Tier 0 pages: {}",
-            entries
-                .iter()
-                .filter(|(_, (_, node))| (*node).0 == 0)
-                .count()
-        );
-        info!(
-            "Tier 1 pages: {}",
-            entries
+        // Check the placement against where the hardware says accesses to
+        // each tier were actually served from.
+        for (i, tier) in self.topology.tiers.iter().enumerate() {
+            let (pages, bytes) = entries
+                .values()
+                .filter(|(_, node, _)| tier.contains(node.0))
+                .fold((0, 0), |(n, bytes), (_, _, size)| (n + 1, bytes + size));
+            info!("Tier {} pages: {} ({} MiB)", i, pages, bytes >> 20);
+            let mut totals = [0; MemSource::COUNT];
+            for (page, counts) in &self.sources {
+                if entries
+                    .get(page)
+                    .is_some_and(|(_, node, _)| tier.contains(node.0))
+                {
+                    totals.iter_mut().zip(counts).for_each(|(t, c)| *t += c);
+                }
+            }
+            let breakdown = MemSource::ALL
                 .iter()
-                .filter(|(_, (_, node))| (*node).0 == 1)
-                .count()
-        );
+                .zip(totals)
+                .filter(|(_, n)| *n > 0)
+                .map(|(source, n)| format!("{:?} {}", source, n))
+                .collect::<Vec<_>>();
+            if !breakdown.is_empty() {
+                info!("Tier {} served from: {}", i, breakdown.join(", "));
+            }
+        }
         let mut entries = entries.iter().collect::<Vec<_>>();
         let sample_n = entries.len().min(10);
         entries.sort_by(|a, b| a.1.partial_cmp(b.1).unwrap());
         for (addr, cost) in entries.iter().rev().take(sample_n) {
             info!(
-                "{:#x}: {:.2}, node: {}, migration fails: {}",
-                addr, cost.0, cost.1 .0, cost.1 .1
+                "{:#x}: {:.2}, node: {}, migration fails: {}, mostly from: {:?}",
+                addr,
+                cost.0,
+                cost.1 .0,
+                cost.1 .1,
+                self.main_source(**addr)
             );
         }
 
@@ -281,67 +445,441 @@ impl Tracker for PolTracker {
             );
         }
     }
-    // Caller must ensure ptr is valid.
-    fn handle_sample(&mut self, sample: *const u8) {
-        let sample: *const demo_record = sample.cast();
+    fn handle_sample(&mut self, sample: &Sample) {
+        if let Some(time) = sample.time {
+            self.clock.fetch_max(time, Ordering::Relaxed);
+        }
+        // IBS samples every op and cannot filter by privilege level, the data
+        // address comes from the raw IbsDcLinAd register. Other events record
+        // an address of 0 when they have none, e.g. cycles.
+        let (addr, phys_addr, latency, source) =
+            if let Some(op) = sample.raw.as_deref().and_then(IbsOp::decode) {
+                if sample.misc as u32 & PERF_RECORD_MISC_CPUMODE_MASK != PERF_RECORD_MISC_USER {
+                    return;
+                }
+                let Some(addr) = op.lin_addr().filter(|_| op.is_load() || op.is_store()) else {
+                    return;
+                };
+                (
+                    addr,
+                    op.phys_addr(),
+                    op.dc_miss_latency(),
+                    Some(op.source()),
+                )
+            } else if let Some(addr) = sample.addr.filter(|a| *a != 0) {
+                let latency = sample.weight.map_or(0, |w| w.latency());
+                let phys_addr = sample.phys_addr.filter(|p| *p != 0);
+                (
+                    addr,
+                    phys_addr,
+                    latency,
+                    sample.data_src.map(|d| d.source()),
+                )
+            } else {
+                return;
+            };
+        // The kernel reports the size of the page behind addr at sample time,
+        // huge pages come and go with khugepaged and splits.
+        self.refresh_page_sizes(sample.time);
+        let (page, size) = self.page_sizes.sampled_page(addr, sample.data_page_size);
+        let cost = self.cost(latency);
+        self.update(page, size, cost);
+        if let Some(phys_addr) = phys_addr {
+            self.place(page, phys_addr);
+        }
+        if let Some(source) = source {
+            self.sources.entry(page).or_insert([0; MemSource::COUNT])[source as usize] += 1;
+        }
+
+        //debug!(
+        //    "[ID: {:?}] {:#02x?},{:?},{:?},{:#02x?}",
+        //    sample.id, sample.ip, sample.tid, sample.time, sample.addr,
+        //);
+    }
 
-        let va = unsafe { (*sample).addr & !(PAGE_SIZE - 1) };
-        self.update(va);
+    fn handle_lost(&mut self, lost: &Lost) {
+        self.lost += lost.lost;
+        warn!("Lost {} records (total {}).", lost.lost, self.lost);
+    }
 
-        //unsafe {
-        //    debug!(
-        //        "[ID: {}] {:#02x},{},{},{:#02x}",
-        //        (*sample).id,
-        //        (*sample).ip,
-        //        (*sample).tid,
-        //        (*sample).time,
-        //        (*sample).addr,
-        //    );
-        //}
+    fn handle_throttle(&mut self, _throttle: &Throttle, throttled: bool) {
+        if throttled {
+            self.throttled += 1;
+            warn!("Sampling throttled by the kernel.");
+        } else {
+            debug!("Sampling unthrottled.");
+        }
     }
+
+    fn handle_mmap(&mut self, mmap: &Mmap2) {
+        if mmap.pid as i32 != self.pid {
+            return;
+        }
+        self.page_sizes_stale = true;
+        // A new mapping replaces whatever overlapped its range.
+        let end = mmap.addr + mmap.len;
+        let overlapping = self
+            .maps
+            .range(..end)
+            .filter(|(_, m)| m.addr + m.len > mmap.addr)
+            .map(|(addr, _)| *addr)
+            .collect::<Vec<_>>();
+        for addr in overlapping {
+            self.maps.remove(&addr);
+        }
+        debug!(
+            "Mapped {:#x}-{:#x} {}",
+            mmap.addr,
+            end,
+            if mmap.filename.is_empty() {
+                "[anon]"
+            } else {
+                &mmap.filename
+            }
+        );
+        self.maps.insert(mmap.addr, mmap.clone());
+    }
+
+    fn handle_comm(&mut self, comm: &Comm) {
+        if comm.pid as i32 == self.pid {
+            debug!("Target {} is now {}.", comm.tid, comm.comm);
+        }
+    }
+
+    fn handle_exit(&mut self, task: &Task) {
+        if task.pid as i32 == self.pid && task.tid == task.pid {
+            info!("Target {} exited.", self.pid);
+            self.exited = true;
+        }
+    }
+
+    fn finished(&self) -> bool {
+        self.exited
+    }
+}
+
+// Members write into the leader's ring and their records are decoded with
+// the leader's attr, so every event of a group samples the same fields.
+// PERF_SAMPLE_DATA_PAGE_SIZE needs Linux 5.11.
+fn group_sample_type(args: &Args, events: &[EventSpec]) -> u64 {
+    let mut sample_type = perf_event_sample_format_PERF_SAMPLE_IDENTIFIER
+        | perf_event_sample_format_PERF_SAMPLE_IP
+        | perf_event_sample_format_PERF_SAMPLE_TID
+        | perf_event_sample_format_PERF_SAMPLE_TIME
+        | perf_event_sample_format_PERF_SAMPLE_ADDR
+        | perf_event_sample_format_PERF_SAMPLE_DATA_PAGE_SIZE;
+    if events.iter().any(|e| e.ibs) {
+        sample_type |= perf_event_sample_format_PERF_SAMPLE_RAW;
+    }
+    if events.iter().any(|e| !e.ibs) {
+        sample_type |= perf_event_sample_format_PERF_SAMPLE_DATA_SRC;
+        if args.cost == CostMode::Latency || events.iter().any(EventSpec::is_load_latency) {
+            sample_type |= perf_event_sample_format_PERF_SAMPLE_WEIGHT;
+        }
+        if args.phys_addr {
+            sample_type |= perf_event_sample_format_PERF_SAMPLE_PHYS_ADDR;
+        }
+    }
+    sample_type as u64
 }
 
 fn build_mem_event(
     args: &Args,
-    event: u64,
+    target: Target,
+    event: &EventSpec,
+    sample_type: u64,
     disabled: bool,
     group: Option<&File>,
 ) -> Result<PerfEvent, PerfError> {
     let mut attr = perf_event_attr {
-        type_: perf_type_id_PERF_TYPE_RAW,
-        config: event,
+        type_: event.type_,
+        config: event.config,
         ..Default::default()
     };
+    attr.__bindgen_anon_3.config1 = event.config1;
+    attr.__bindgen_anon_4.config2 = event.config2;
     attr.set_sample_period(args.sample_period);
-    attr.sample_type = (perf_event_sample_format_PERF_SAMPLE_IDENTIFIER
-        | perf_event_sample_format_PERF_SAMPLE_IP
-        | perf_event_sample_format_PERF_SAMPLE_TID
-        | perf_event_sample_format_PERF_SAMPLE_TIME
-        | perf_event_sample_format_PERF_SAMPLE_ADDR) as u64;
+    attr.sample_type = sample_type;
+    if event.ibs {
+        // The low 4 bits of the IBS max count are reserved.
+        attr.set_sample_period((args.sample_period & !0xf).max(0x10));
+    }
     attr.__bindgen_anon_2.wakeup_events = (args.sample_period / 4) as u32;
     //attr.set_watermark(1); // Set this for wakeup watermark
     if disabled {
         attr.set_disabled(1);
+        // A launched workload is traced from its first instruction. The
+        // kernel only applies enable_on_exec to events bound to the task on
+        // every cpu, main enables cpu bound groups itself.
+        if !args.command.is_empty() && target.cpu == -1 {
+            attr.set_enable_on_exec(1);
+        }
+    }
+    if group.is_none() {
+        // Side-band records only need to be emitted once, by the group leader
+        // that owns the ring buffer.
+        attr.set_mmap(1);
+        attr.set_mmap2(1);
+        attr.set_mmap_data(1);
+        attr.set_comm(1);
+        attr.set_task(1);
+    }
+    // Older kernels refuse any exclude bit on IBS, kernel samples are
+    // dropped in handle_sample instead.
+    if !event.ibs {
+        attr.set_exclude_kernel(1);
+        attr.set_exclude_hv(1);
     }
-    attr.set_exclude_kernel(1);
-    attr.set_exclude_hv(1);
     attr.set_exclude_callchain_user(1);
     attr.set_exclude_callchain_kernel(1);
     attr.set_precise_ip(2);
-    PerfEvent::new(attr, args.pid, args.cpu, group, 0)
+    PerfEvent::new(
+        attr,
+        target.pid,
+        target.cpu,
+        group,
+        target.flags,
+        args.mmap_pages,
+    )
+}
+
+// The first event leads the group and owns the ring, the rest are best effort.
+fn open_mem_group(
+    args: &Args,
+    events: &[EventSpec],
+    target: Target,
+) -> Result<Vec<PerfEvent>, PerfError> {
+    let Some((leader, members)) = events.split_first() else {
+        error!("No events to open.");
+        return Err(PerfError::UnknownEvent);
+    };
+    let sample_type = group_sample_type(args, events);
+    let leader = build_mem_event(args, target, leader, sample_type, true, None)?;
+    let mut group = Vec::with_capacity(events.len());
+    for event in members {
+        let member = build_mem_event(
+            args,
+            target,
+            event,
+            sample_type,
+            false,
+            Some(leader.get_fd()),
+        );
+        match member {
+            Ok(member) => group.push(member),
+            Err(_) => warn!("Failed to open {} on cpu {}.", event.name, target.cpu),
+        }
+    }
+    group.insert(0, leader);
+    Ok(group)
+}
+
+// Open a disabled group per thread of args.pid and follow threads spawned
+// later. Threads spawned before the groups are enabled are picked up by
+// rescanning once they are.
+fn open_thread_groups(
+    args: &Args,
+    specs: &[EventSpec],
+    events: &mut PerfEventSet,
+) -> Result<(), PerfError> {
+    let args = args.clone();
+    let specs = specs.to_vec();
+    events.follow_threads(
+        args.pid,
+        Box::new(move |tid| {
+            let target = Target {
+                pid: tid,
+                cpu: args.cpu,
+                flags: 0,
+            };
+            open_mem_group(&args, &specs, target)
+        }),
+    );
+    events.scan_threads(false)?;
+    Ok(())
+}
+
+// Memory events for the cpu we are running on, loads first. With --ldlat the
+// loads are sampled by latency instead of by cache level.
+fn default_events(args: &Args) -> Result<Vec<EventSpec>, PerfError> {
+    match CpuModel::detect()?.mem_events()? {
+        MemEvents::Pebs { stores, .. } if args.ldlat.is_some() => Ok(vec![
+            EventSpec::raw("mem_trans_retired.load_latency", LOAD_LATENCY),
+            stores,
+        ]),
+        MemEvents::Pebs { loads, stores } => Ok(vec![loads, stores]),
+        MemEvents::Ibs => Ok(vec![ibs::op_event()?]),
+    }
+}
+
+// Put mem-loads-aux in front of load latency events on cpus that need it.
+fn add_mem_loads_aux(specs: &mut Vec<EventSpec>) {
+    let needs_aux = CpuModel::detect()
+        .ok()
+        .and_then(|cpu| cpu.uarch())
+        .is_some_and(|uarch| uarch.needs_mem_loads_aux());
+    let has_aux = specs.first().is_some_and(EventSpec::is_mem_loads_aux);
+    if needs_aux && !has_aux && specs.iter().any(EventSpec::is_load_latency) {
+        debug!("Adding mem-loads-aux as group leader.");
+        specs.insert(0, EventSpec::raw("mem-loads-aux", MEM_LOADS_AUX));
+    }
 }
 
 fn main() {
     env_logger::init();
-    let args = Args::parse();
-    let mut tracker = PolTracker::new(args.pid);
+    let mut args = Args::parse();
+    // Aging shrinks costs every round, LRU would take that for pages not
+    // being sampled any more.
+    if args.policy == PolicyKind::Lru && args.aging != AgingMode::None {
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--policy lru cannot be combined with --aging",
+            )
+            .exit();
+    }
+    // Fork before any other thread exists, the child execs once events are open.
+    let mut workload = if args.command.is_empty() {
+        None
+    } else {
+        let workload = Workload::spawn(&args.command).unwrap();
+        args.pid = workload.pid();
+        Some(workload)
+    };
+    // Block the stop signals before the policy thread is spawned so they are
+    // only ever delivered through the signalfd.
+    let signals = SignalFd::new(&[libc::SIGINT, libc::SIGTERM]).unwrap();
+    let perfmon = args.perfmon.as_ref().map(|p| Perfmon::load(p).unwrap());
+    let mut specs = if args.events.is_empty() {
+        default_events(&args).unwrap()
+    } else {
+        args.events
+            .iter()
+            .map(|e| EventSpec::parse(e, perfmon.as_ref()))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap()
+    };
+    if let Some(ldlat) = args.ldlat {
+        for spec in specs.iter_mut().filter(|s| s.is_load_latency()) {
+            spec.config1 = ldlat;
+        }
+    }
+    add_mem_loads_aux(&mut specs);
+    let blocks = args.phys_addr.then(|| MemoryBlocks::load().unwrap());
+    let topology = Arc::new(TierTopology::load(args.tiers.as_deref()).unwrap());
+    let watermarks = Watermarks {
+        low: args.low_watermark,
+        high: args.high_watermark.max(args.low_watermark),
+    };
+    let aging = Aging::new(args.aging, Duration::from_millis(args.aging_period));
+    let strategy: Box<dyn MigrationPolicy> = match args.policy {
+        PolicyKind::Watermark => Box::new(WatermarkPolicy { watermarks }),
+        PolicyKind::Ratio => Box::new(RatioPolicy),
+        PolicyKind::Topk => Box::new(TopKPolicy { watermarks }),
+        PolicyKind::Threshold => Box::new(ThresholdPolicy {
+            hot: args.hot_threshold,
+            cold: args.cold_threshold,
+            watermarks,
+        }),
+        PolicyKind::Lru => Box::new(LruPolicy::new(args.lru_rounds, watermarks)),
+    };
+    let dry_run = args
+        .dry_run
+        .then(|| DryRun::new(args.decision_log.as_deref()).unwrap());
+    let limit = RateLimit::new(args.migrate_mbps, args.migrate_pages);
+    let policy = Policy::new(
+        Duration::from_millis(args.interval),
+        topology.clone(),
+        aging,
+        strategy,
+        limit,
+        dry_run,
+    );
+    // The kernel needs one event per cpu for cgroup and system wide tracing.
+    let cgroup = args.cgroup.as_ref().map(|path| File::open(path).unwrap());
+    // Page addresses of different processes collide, so without a single
+    // target pages are only tracked, never queried or migrated.
+    let target_pid = if cgroup.is_some() { -1 } else { args.pid };
+    if target_pid == -1 {
+        warn!("No single target process, pages will not be migrated.");
+    }
+    if args.dry_run && !args.phys_addr {
+        warn!("A dry run places pages only with --phys-addr, no decisions will be made.");
+    }
+    let mut tracker = PolTracker::new(
+        target_pid,
+        args.cost,
+        topology,
+        policy,
+        blocks,
+        !args.dry_run,
+    );
     tracker.start_policy();
-    let mem_read = build_mem_event(&args, L3MISS, true, None).unwrap();
-    let _mem_store = build_mem_event(&args, ALLSTORES, false, Some(mem_read.get_fd()));
-    mem_read.reset().unwrap();
-    mem_read.enable().unwrap();
-    mem_read
-        .sample_loop(size_of::<demo_record>(), &mut tracker)
-        .unwrap();
+    let (pid, flags) = match cgroup.as_ref() {
+        Some(cgroup) => (cgroup.as_raw_fd(), PERF_FLAG_PID_CGROUP as i32),
+        None => (args.pid, 0),
+    };
+    // Threads that already exist are only traced by a group of their own, so
+    // a single target is always followed thread by thread.
+    let per_thread = args.threads && cgroup.is_none() && args.pid != -1;
+    if per_thread && args.per_cpu {
+        warn!("--per-cpu is ignored with --threads.");
+    }
+    let per_cpu = args.cpu == -1 && (args.per_cpu || cgroup.is_some() || args.pid == -1);
+    let mut events = PerfEventSet::new();
+    if per_thread {
+        open_thread_groups(&args, &specs, &mut events).unwrap();
+    } else {
+        let cpus = if per_cpu {
+            perf::online_cpus().unwrap()
+        } else {
+            vec![args.cpu]
+        };
+        for cpu in cpus {
+            let target = Target { pid, cpu, flags };
+            events
+                .add_group(open_mem_group(&args, &specs, target).unwrap())
+                .unwrap();
+        }
+    }
+    info!(
+        "Opened {} event groups with {} KiB rings.",
+        events.len(),
+        (args.mmap_pages * PAGE_SIZE as usize) >> 10
+    );
+    events.reset().unwrap();
+    let cpu_bound = per_cpu || args.cpu != -1;
+    match workload.as_mut() {
+        Some(workload) => {
+            // Enabled just before the exec, the few samples of the child
+            // still running tracem land on pages the exec unmaps.
+            if cpu_bound {
+                events.enable().unwrap();
+            }
+            workload.start().unwrap()
+        }
+        None => {
+            events.enable().unwrap();
+            // Threads spawned while the groups were still disabled left no
+            // FORK record behind.
+            events.scan_threads(true).unwrap();
+        }
+    }
+    events.stop_on_signals(signals.try_clone().unwrap());
+    if let Some(secs) = args.duration {
+        events.stop_after(Duration::from_secs(secs));
+    }
+    let reason = events.sample_loop(&mut tracker).unwrap();
+    // Close the rings before summarizing, nothing more is read from them.
+    drop(events);
     tracker.debug_summary();
+    if let Some(mut workload) = workload {
+        match reason {
+            StopReason::Signal(sig) => workload.kill(sig).unwrap(),
+            StopReason::Timeout => workload.kill(libc::SIGTERM).unwrap(),
+            StopReason::Closed | StopReason::Finished => {}
+        }
+        // Still blocked, a second Ctrl-C is read here and kills the workload.
+        workload.wait_signaled(&signals).unwrap();
+    }
 }
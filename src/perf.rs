@@ -1,26 +1,34 @@
 use std::{
+    collections::{HashMap, HashSet},
     fs::File,
-    mem::zeroed,
+    mem::{size_of, zeroed},
     os::fd::{AsRawFd, FromRawFd},
-    ptr::copy_nonoverlapping,
+    ptr::addr_of_mut,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
 };
 
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 
 pub mod perf_sys {
     include!(concat!(env!("OUT_DIR"), "/perf-sys.rs"));
 }
 
 use mio::{unix::SourceFd, Events, Interest, Poll, Token};
-pub use perf_sys::*;
 
-const MMAP_PAGES: usize = 1 + (1 << 16); // 1MiB
+use crate::signal::SignalFd;
+pub use perf_sys::*;
 
 #[derive(Debug)]
 pub enum PerfError {
     EventOpen,
     Mmap,
     Poll,
+    Parse,
+    Io,
+    UnknownEvent,
+    Unsupported,
+    UnsupportedCpu,
 }
 
 impl Default for perf_event_header {
@@ -52,7 +60,580 @@ impl perf_event_attr {
     }
 }
 
+/// Cursor over the raw bytes of a single perf record.
+struct RecordReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> RecordReader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], PerfError> {
+        match self.pos.checked_add(n) {
+            Some(end) if end <= self.buf.len() => {
+                let bytes = &self.buf[self.pos..end];
+                self.pos = end;
+                Ok(bytes)
+            }
+            _ => {
+                error!(
+                    "Record truncated: need {} bytes at offset {}, record is {} bytes.",
+                    n,
+                    self.pos,
+                    self.buf.len()
+                );
+                Err(PerfError::Parse)
+            }
+        }
+    }
+
+    fn u64(&mut self) -> Result<u64, PerfError> {
+        Ok(u64::from_ne_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, PerfError> {
+        Ok(u32::from_ne_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u16(&mut self) -> Result<u16, PerfError> {
+        Ok(u16::from_ne_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u64_array(&mut self, n: usize) -> Result<Vec<u64>, PerfError> {
+        (0..n).map(|_| self.u64()).collect()
+    }
+
+    /// NUL terminated string padded out to a multiple of 8 bytes.
+    fn c_string(&mut self) -> Result<String, PerfError> {
+        let rest = &self.buf[self.pos..];
+        let Some(len) = rest.iter().position(|b| *b == 0) else {
+            error!("Unterminated string in record.");
+            return Err(PerfError::Parse);
+        };
+        let s = String::from_utf8_lossy(&rest[..len]).into_owned();
+        self.bytes((len + 1).next_multiple_of(8))?;
+        Ok(s)
+    }
+}
+
+/// Value of PERF_SAMPLE_WEIGHT or PERF_SAMPLE_WEIGHT_STRUCT.
+#[derive(Debug, Clone, Copy)]
+pub enum Weight {
+    Full(u64),
+    Struct {
+        var1_dw: u32,
+        var2_w: u16,
+        var3_w: u16,
+    },
+}
+
+impl Weight {
+    /// Access latency in cycles. For the struct layout this is var1_dw, the
+    /// other fields hold pmu specific values (e.g. instruction latency).
+    pub fn latency(&self) -> u64 {
+        match self {
+            Weight::Full(w) => *w,
+            Weight::Struct { var1_dw, .. } => *var1_dw as u64,
+        }
+    }
+}
+
+/// Where a sampled access was served from, coarse enough to compare with
+/// the tier the page is placed on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MemSource {
+    L1,
+    L2,
+    L3,
+    RemoteCache,
+    LocalDram,
+    RemoteDram,
+    Pmem,
+    Cxl,
+    Io,
+    Unknown,
+}
+
+impl MemSource {
+    pub const COUNT: usize = 10;
+    pub const ALL: [MemSource; Self::COUNT] = [
+        MemSource::L1,
+        MemSource::L2,
+        MemSource::L3,
+        MemSource::RemoteCache,
+        MemSource::LocalDram,
+        MemSource::RemoteDram,
+        MemSource::Pmem,
+        MemSource::Cxl,
+        MemSource::Io,
+        MemSource::Unknown,
+    ];
+}
+
+/// PERF_SAMPLE_DATA_SRC, the union perf_mem_data_src unpacked.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DataSrc {
+    pub mem_op: u8,
+    pub mem_lvl: u16,
+    pub mem_snoop: u8,
+    pub mem_lock: u8,
+    pub mem_dtlb: u8,
+    pub mem_lvl_num: u8,
+    pub mem_remote: bool,
+    pub mem_snoopx: u8,
+    pub mem_blk: u8,
+    pub mem_hops: u8,
+}
+
+impl DataSrc {
+    pub fn from_raw(v: u64) -> Self {
+        let bits = |shift: u32, width: u32| (v >> shift) & ((1 << width) - 1);
+        Self {
+            mem_op: bits(0, 5) as u8,
+            mem_lvl: bits(PERF_MEM_LVL_SHIFT, 14) as u16,
+            mem_snoop: bits(19, 5) as u8,
+            mem_lock: bits(24, 2) as u8,
+            mem_dtlb: bits(26, 7) as u8,
+            mem_lvl_num: bits(PERF_MEM_LVLNUM_SHIFT, 4) as u8,
+            mem_remote: bits(PERF_MEM_REMOTE_SHIFT, 1) == PERF_MEM_REMOTE_REMOTE as u64,
+            mem_snoopx: bits(38, 2) as u8,
+            mem_blk: bits(40, 3) as u8,
+            mem_hops: bits(43, 3) as u8,
+        }
+    }
+
+    /// Classify by mem_lvl_num, falling back to the older mem_lvl bits for
+    /// kernels and PMUs that do not fill it in.
+    pub fn source(&self) -> MemSource {
+        let remote = self.mem_remote;
+        match self.mem_lvl_num as u32 {
+            PERF_MEM_LVLNUM_L1 | PERF_MEM_LVLNUM_LFB => MemSource::L1,
+            PERF_MEM_LVLNUM_L2 => MemSource::L2,
+            PERF_MEM_LVLNUM_L3 | PERF_MEM_LVLNUM_L4 | PERF_MEM_LVLNUM_ANY_CACHE if remote => {
+                MemSource::RemoteCache
+            }
+            PERF_MEM_LVLNUM_L3 | PERF_MEM_LVLNUM_L4 | PERF_MEM_LVLNUM_ANY_CACHE => MemSource::L3,
+            PERF_MEM_LVLNUM_RAM if remote => MemSource::RemoteDram,
+            PERF_MEM_LVLNUM_RAM => MemSource::LocalDram,
+            PERF_MEM_LVLNUM_PMEM => MemSource::Pmem,
+            PERF_MEM_LVLNUM_CXL => MemSource::Cxl,
+            PERF_MEM_LVLNUM_IO => MemSource::Io,
+            _ => {
+                let lvl = self.mem_lvl as u32;
+                if lvl & (PERF_MEM_LVL_L1 | PERF_MEM_LVL_LFB) != 0 {
+                    MemSource::L1
+                } else if lvl & PERF_MEM_LVL_L2 != 0 {
+                    MemSource::L2
+                } else if lvl & PERF_MEM_LVL_L3 != 0 {
+                    MemSource::L3
+                } else if lvl & PERF_MEM_LVL_LOC_RAM != 0 {
+                    MemSource::LocalDram
+                } else if lvl & (PERF_MEM_LVL_REM_RAM1 | PERF_MEM_LVL_REM_RAM2) != 0 {
+                    MemSource::RemoteDram
+                } else if lvl & (PERF_MEM_LVL_REM_CCE1 | PERF_MEM_LVL_REM_CCE2) != 0 {
+                    MemSource::RemoteCache
+                } else if lvl & PERF_MEM_LVL_IO != 0 {
+                    MemSource::Io
+                } else {
+                    MemSource::Unknown
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ReadValue {
+    pub value: u64,
+    pub id: Option<u64>,
+    pub lost: Option<u64>,
+}
+
+/// PERF_SAMPLE_READ payload, layout given by attr.read_format.
+#[derive(Debug, Clone, Default)]
+pub struct ReadFormat {
+    pub time_enabled: Option<u64>,
+    pub time_running: Option<u64>,
+    pub values: Vec<ReadValue>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BranchEntry {
+    pub from: u64,
+    pub to: u64,
+    pub flags: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BranchStack {
+    pub hw_idx: Option<u64>,
+    pub entries: Vec<BranchEntry>,
+}
+
+/// PERF_SAMPLE_REGS_USER/REGS_INTR payload. regs is empty when abi is
+/// PERF_SAMPLE_REGS_ABI_NONE.
+#[derive(Debug, Clone, Default)]
+pub struct Regs {
+    pub abi: u64,
+    pub regs: Vec<u64>,
+}
+
+/// A decoded PERF_RECORD_SAMPLE. Fields are present iff the corresponding
+/// PERF_SAMPLE_* bit was set in the sample_type of the event that produced
+/// the record.
+#[derive(Debug, Clone, Default)]
+pub struct Sample {
+    pub misc: u16,
+    pub id: Option<u64>,
+    pub ip: Option<u64>,
+    pub pid: Option<u32>,
+    pub tid: Option<u32>,
+    pub time: Option<u64>,
+    pub addr: Option<u64>,
+    pub stream_id: Option<u64>,
+    pub cpu: Option<u32>,
+    pub period: Option<u64>,
+    pub read: Option<ReadFormat>,
+    pub callchain: Option<Vec<u64>>,
+    pub raw: Option<Vec<u8>>,
+    pub branch_stack: Option<BranchStack>,
+    pub regs_user: Option<Regs>,
+    pub stack_user: Option<Vec<u8>>,
+    pub weight: Option<Weight>,
+    pub data_src: Option<DataSrc>,
+    pub transaction: Option<u64>,
+    pub regs_intr: Option<Regs>,
+    pub phys_addr: Option<u64>,
+    pub cgroup: Option<u64>,
+    pub data_page_size: Option<u64>,
+    pub code_page_size: Option<u64>,
+    pub aux: Option<Vec<u8>>,
+}
+
+impl Sample {
+    /// Decode a PERF_RECORD_SAMPLE (including its perf_event_header) using the
+    /// sample_type and related fields of the attr the event was opened with.
+    /// Fields are laid out in the order documented in perf_event_open(2).
+    pub fn parse(attr: &perf_event_attr, record: &[u8]) -> Result<Self, PerfError> {
+        let mut r = RecordReader::new(record);
+        let hdr_bytes = r.bytes(size_of::<perf_event_header>())?;
+        // SAFETY: hdr_bytes is exactly size_of::<perf_event_header>() bytes long.
+        let hdr: perf_event_header = unsafe { std::ptr::read_unaligned(hdr_bytes.as_ptr().cast()) };
+        if hdr.type_ != perf_event_type_PERF_RECORD_SAMPLE {
+            error!("Record type {} is not a sample.", hdr.type_);
+            return Err(PerfError::Parse);
+        }
+        let has = |bit: perf_event_sample_format| attr.sample_type & bit as u64 != 0;
+        let mut s = Sample {
+            misc: hdr.misc,
+            ..Default::default()
+        };
+
+        if has(perf_event_sample_format_PERF_SAMPLE_IDENTIFIER) {
+            s.id = Some(r.u64()?);
+        }
+        if has(perf_event_sample_format_PERF_SAMPLE_IP) {
+            s.ip = Some(r.u64()?);
+        }
+        if has(perf_event_sample_format_PERF_SAMPLE_TID) {
+            s.pid = Some(r.u32()?);
+            s.tid = Some(r.u32()?);
+        }
+        if has(perf_event_sample_format_PERF_SAMPLE_TIME) {
+            s.time = Some(r.u64()?);
+        }
+        if has(perf_event_sample_format_PERF_SAMPLE_ADDR) {
+            s.addr = Some(r.u64()?);
+        }
+        if has(perf_event_sample_format_PERF_SAMPLE_ID) {
+            s.id = Some(r.u64()?);
+        }
+        if has(perf_event_sample_format_PERF_SAMPLE_STREAM_ID) {
+            s.stream_id = Some(r.u64()?);
+        }
+        if has(perf_event_sample_format_PERF_SAMPLE_CPU) {
+            s.cpu = Some(r.u32()?);
+            let _res = r.u32()?;
+        }
+        if has(perf_event_sample_format_PERF_SAMPLE_PERIOD) {
+            s.period = Some(r.u64()?);
+        }
+        if has(perf_event_sample_format_PERF_SAMPLE_READ) {
+            s.read = Some(parse_read_format(attr.read_format, &mut r)?);
+        }
+        if has(perf_event_sample_format_PERF_SAMPLE_CALLCHAIN) {
+            let nr = r.u64()? as usize;
+            s.callchain = Some(r.u64_array(nr)?);
+        }
+        if has(perf_event_sample_format_PERF_SAMPLE_RAW) {
+            // The u32 size and the raw data together are padded to 8 bytes.
+            let size = r.u32()? as usize;
+            s.raw = Some(r.bytes(size)?.to_vec());
+        }
+        if has(perf_event_sample_format_PERF_SAMPLE_BRANCH_STACK) {
+            let bnr = r.u64()? as usize;
+            let hw_idx = if attr.branch_sample_type
+                & perf_branch_sample_type_PERF_SAMPLE_BRANCH_HW_INDEX as u64
+                != 0
+            {
+                Some(r.u64()?)
+            } else {
+                None
+            };
+            let entries = (0..bnr)
+                .map(|_| {
+                    Ok(BranchEntry {
+                        from: r.u64()?,
+                        to: r.u64()?,
+                        flags: r.u64()?,
+                    })
+                })
+                .collect::<Result<Vec<_>, PerfError>>()?;
+            s.branch_stack = Some(BranchStack { hw_idx, entries });
+        }
+        if has(perf_event_sample_format_PERF_SAMPLE_REGS_USER) {
+            s.regs_user = Some(parse_regs(attr.sample_regs_user, &mut r)?);
+        }
+        if has(perf_event_sample_format_PERF_SAMPLE_STACK_USER) {
+            let size = r.u64()? as usize;
+            let data = r.bytes(size)?;
+            if size != 0 {
+                let dyn_size = r.u64()? as usize;
+                s.stack_user = Some(data[..dyn_size.min(size)].to_vec());
+            } else {
+                s.stack_user = Some(Vec::new());
+            }
+        }
+        if has(perf_event_sample_format_PERF_SAMPLE_WEIGHT_STRUCT) {
+            s.weight = Some(Weight::Struct {
+                var1_dw: r.u32()?,
+                var2_w: r.u16()?,
+                var3_w: r.u16()?,
+            });
+        } else if has(perf_event_sample_format_PERF_SAMPLE_WEIGHT) {
+            s.weight = Some(Weight::Full(r.u64()?));
+        }
+        if has(perf_event_sample_format_PERF_SAMPLE_DATA_SRC) {
+            s.data_src = Some(DataSrc::from_raw(r.u64()?));
+        }
+        if has(perf_event_sample_format_PERF_SAMPLE_TRANSACTION) {
+            s.transaction = Some(r.u64()?);
+        }
+        if has(perf_event_sample_format_PERF_SAMPLE_REGS_INTR) {
+            s.regs_intr = Some(parse_regs(attr.sample_regs_intr, &mut r)?);
+        }
+        if has(perf_event_sample_format_PERF_SAMPLE_PHYS_ADDR) {
+            s.phys_addr = Some(r.u64()?);
+        }
+        if has(perf_event_sample_format_PERF_SAMPLE_CGROUP) {
+            s.cgroup = Some(r.u64()?);
+        }
+        if has(perf_event_sample_format_PERF_SAMPLE_DATA_PAGE_SIZE) {
+            s.data_page_size = Some(r.u64()?);
+        }
+        if has(perf_event_sample_format_PERF_SAMPLE_CODE_PAGE_SIZE) {
+            s.code_page_size = Some(r.u64()?);
+        }
+        if has(perf_event_sample_format_PERF_SAMPLE_AUX) {
+            let size = r.u64()? as usize;
+            s.aux = Some(r.bytes(size)?.to_vec());
+        }
+        Ok(s)
+    }
+}
+
+fn parse_read_format(read_format: u64, r: &mut RecordReader) -> Result<ReadFormat, PerfError> {
+    let has = |bit: perf_event_read_format| read_format & bit as u64 != 0;
+    let read_value = |r: &mut RecordReader, value: u64| -> Result<ReadValue, PerfError> {
+        Ok(ReadValue {
+            value,
+            id: if has(perf_event_read_format_PERF_FORMAT_ID) {
+                Some(r.u64()?)
+            } else {
+                None
+            },
+            lost: if has(perf_event_read_format_PERF_FORMAT_LOST) {
+                Some(r.u64()?)
+            } else {
+                None
+            },
+        })
+    };
+    let mut read = ReadFormat::default();
+    if has(perf_event_read_format_PERF_FORMAT_GROUP) {
+        let nr = r.u64()? as usize;
+        if has(perf_event_read_format_PERF_FORMAT_TOTAL_TIME_ENABLED) {
+            read.time_enabled = Some(r.u64()?);
+        }
+        if has(perf_event_read_format_PERF_FORMAT_TOTAL_TIME_RUNNING) {
+            read.time_running = Some(r.u64()?);
+        }
+        for _ in 0..nr {
+            let value = r.u64()?;
+            read.values.push(read_value(r, value)?);
+        }
+    } else {
+        let value = r.u64()?;
+        if has(perf_event_read_format_PERF_FORMAT_TOTAL_TIME_ENABLED) {
+            read.time_enabled = Some(r.u64()?);
+        }
+        if has(perf_event_read_format_PERF_FORMAT_TOTAL_TIME_RUNNING) {
+            read.time_running = Some(r.u64()?);
+        }
+        read.values.push(read_value(r, value)?);
+    }
+    Ok(read)
+}
+
+fn parse_regs(mask: u64, r: &mut RecordReader) -> Result<Regs, PerfError> {
+    let abi = r.u64()?;
+    let regs = if abi != 0 {
+        r.u64_array(mask.count_ones() as usize)?
+    } else {
+        Vec::new()
+    };
+    Ok(Regs { abi, regs })
+}
+
+/// PERF_RECORD_LOST: the kernel dropped `lost` records because the ring was full.
+#[derive(Debug, Clone, Copy)]
+pub struct Lost {
+    pub id: u64,
+    pub lost: u64,
+}
+
+/// PERF_RECORD_THROTTLE/UNTHROTTLE: sampling was (un)throttled by the kernel
+/// because the interrupt rate exceeded perf_event_max_sample_rate.
+#[derive(Debug, Clone, Copy)]
+pub struct Throttle {
+    pub time: u64,
+    pub id: u64,
+    pub stream_id: u64,
+}
+
+/// PERF_RECORD_MMAP2: a new mapping in the traced process.
+#[derive(Debug, Clone)]
+pub struct Mmap2 {
+    pub misc: u16,
+    pub pid: u32,
+    pub tid: u32,
+    pub addr: u64,
+    pub len: u64,
+    pub pgoff: u64,
+    pub maj: u32,
+    pub min: u32,
+    pub ino: u64,
+    pub ino_generation: u64,
+    pub prot: u32,
+    pub flags: u32,
+    pub filename: String,
+}
+
+/// PERF_RECORD_COMM: a task changed its name, e.g. on exec.
+#[derive(Debug, Clone)]
+pub struct Comm {
+    pub misc: u16,
+    pub pid: u32,
+    pub tid: u32,
+    pub comm: String,
+}
+
+/// PERF_RECORD_FORK/EXIT payload.
+#[derive(Debug, Clone, Copy)]
+pub struct Task {
+    pub pid: u32,
+    pub ppid: u32,
+    pub tid: u32,
+    pub ptid: u32,
+    pub time: u64,
+}
+
+/// A decoded record from the perf ring buffer. Any sample_id_all trailer on
+/// non-sample records is ignored.
+#[derive(Debug, Clone)]
+pub enum Record {
+    Sample(Box<Sample>),
+    Lost(Lost),
+    Throttle(Throttle),
+    Unthrottle(Throttle),
+    Mmap2(Mmap2),
+    Comm(Comm),
+    Fork(Task),
+    Exit(Task),
+    /// Any record type we don't decode, carrying its perf_event_type.
+    Other(u32),
+}
+
+impl Record {
+    /// Decode a record (including its perf_event_header). attr must be the attr
+    /// of the event that owns the ring buffer.
+    pub fn parse(attr: &perf_event_attr, record: &[u8]) -> Result<Self, PerfError> {
+        let mut r = RecordReader::new(record);
+        let hdr_bytes = r.bytes(size_of::<perf_event_header>())?;
+        // SAFETY: hdr_bytes is exactly size_of::<perf_event_header>() bytes long.
+        let hdr: perf_event_header = unsafe { std::ptr::read_unaligned(hdr_bytes.as_ptr().cast()) };
+        let rec = match hdr.type_ {
+            perf_event_type_PERF_RECORD_SAMPLE => {
+                Record::Sample(Box::new(Sample::parse(attr, record)?))
+            }
+            perf_event_type_PERF_RECORD_LOST => Record::Lost(Lost {
+                id: r.u64()?,
+                lost: r.u64()?,
+            }),
+            perf_event_type_PERF_RECORD_THROTTLE => Record::Throttle(parse_throttle(&mut r)?),
+            perf_event_type_PERF_RECORD_UNTHROTTLE => Record::Unthrottle(parse_throttle(&mut r)?),
+            perf_event_type_PERF_RECORD_MMAP2 => Record::Mmap2(Mmap2 {
+                misc: hdr.misc,
+                pid: r.u32()?,
+                tid: r.u32()?,
+                addr: r.u64()?,
+                len: r.u64()?,
+                pgoff: r.u64()?,
+                maj: r.u32()?,
+                min: r.u32()?,
+                ino: r.u64()?,
+                ino_generation: r.u64()?,
+                prot: r.u32()?,
+                flags: r.u32()?,
+                filename: r.c_string()?,
+            }),
+            perf_event_type_PERF_RECORD_COMM => Record::Comm(Comm {
+                misc: hdr.misc,
+                pid: r.u32()?,
+                tid: r.u32()?,
+                comm: r.c_string()?,
+            }),
+            perf_event_type_PERF_RECORD_FORK => Record::Fork(parse_task(&mut r)?),
+            perf_event_type_PERF_RECORD_EXIT => Record::Exit(parse_task(&mut r)?),
+            other => Record::Other(other),
+        };
+        Ok(rec)
+    }
+}
+
+fn parse_throttle(r: &mut RecordReader) -> Result<Throttle, PerfError> {
+    Ok(Throttle {
+        time: r.u64()?,
+        id: r.u64()?,
+        stream_id: r.u64()?,
+    })
+}
+
+fn parse_task(r: &mut RecordReader) -> Result<Task, PerfError> {
+    Ok(Task {
+        pid: r.u32()?,
+        ppid: r.u32()?,
+        tid: r.u32()?,
+        ptid: r.u32()?,
+        time: r.u64()?,
+    })
+}
+
 pub struct PerfEvent {
+    attr: perf_event_attr,
     fd: File,
     mmap_hdr: Option<*mut perf_event_mmap_page>,
     mmap_size: usize,
@@ -65,6 +646,7 @@ impl PerfEvent {
         cpu: i32,
         group: Option<&File>,
         flags: i32,
+        mmap_pages: usize,
     ) -> Result<Self, PerfError> {
         let group_fd = match group {
             Some(g) => g.as_raw_fd(),
@@ -86,6 +668,7 @@ impl PerfEvent {
         // SAFETY: Can only be one owner of the file descriptor.
         let mut event = unsafe {
             Self {
+                attr,
                 fd: File::from_raw_fd(fd),
                 mmap_hdr: None,
                 mmap_size: 0,
@@ -95,7 +678,7 @@ impl PerfEvent {
             // SAFETY: fd is valid. If mmap fails, we return an error.
             // On drop, PerfEvent struct will munmap the buffer.
             unsafe {
-                let (mmap_hdr, mmap_size) = mmap_perf_buffer(&event.fd, MMAP_PAGES)?;
+                let (mmap_hdr, mmap_size) = mmap_perf_buffer(&event.fd, 1 + mmap_pages)?;
                 event.mmap_hdr = Some(mmap_hdr);
                 event.mmap_size = mmap_size;
             }
@@ -138,111 +721,413 @@ impl PerfEvent {
         &self.fd
     }
 
-    /// Main event loop for reading samples from the perf sample buffer.
-    /// Only valid if the perf event was created with a sample period/freq.
-    /// Caller provides a function, f, and a record type, record, to be called
-    /// in the event loop for SAMPLE_RECORD types *only*. This allows the caller
-    /// to define how each sample record is processed.
-    /// struct. This function then blindly trusts
-    pub fn sample_loop<T: super::Tracker>(
+    /// Consume every record currently in the ring buffer. Records are walked by
+    /// their perf_event_header.size, so records of any type and length keep
+    /// the reader in sync with the kernel. FORK and EXIT records are also
+    /// appended to tasks. Returns the number of records read.
+    fn drain<T: super::Tracker>(
         &self,
-        record_size: usize,
+        record_buf: &mut Vec<u8>,
         tracker: &mut T,
-    ) -> Result<(), PerfError> {
-        if let Some(mmap) = self.mmap_hdr {
-            // SAFETY: mmap ptr is valid. Data offset into mmap region is valid.
-            let sample_region = {
-                let begin = unsafe { mmap.byte_add((*mmap).data_offset as usize) };
-                if begin <= mmap {
-                    error!("Data offset is invalid: Less than or equal to mmap hdr.");
-                    return Err(PerfError::Mmap);
-                } else if begin > unsafe { mmap.byte_add(self.mmap_size) } {
-                    error!("Data offset is invalid: Greater than mmap size.");
-                    return Err(PerfError::Mmap);
-                } else {
-                    begin
+        tasks: &mut Vec<Record>,
+    ) -> Result<usize, PerfError> {
+        let Some(mmap) = self.mmap_hdr else {
+            error!("No perf buffer to read samples from.");
+            return Err(PerfError::Mmap);
+        };
+        // SAFETY: mmap ptr is valid for the lifetime of self. The kernel only
+        // writes data_head and only reads data_tail, both are naturally aligned
+        // u64s so they can be accessed atomically.
+        let (data_head, data_tail, data_offset, data_size) = unsafe {
+            (
+                &*(addr_of_mut!((*mmap).data_head) as *const AtomicU64),
+                &*(addr_of_mut!((*mmap).data_tail) as *const AtomicU64),
+                (*mmap).data_offset as usize,
+                (*mmap).data_size as usize,
+            )
+        };
+        if data_offset == 0 || data_offset + data_size > self.mmap_size {
+            error!(
+                "Data region is invalid: offset {:#x}, size {:#x}.",
+                data_offset, data_size
+            );
+            return Err(PerfError::Mmap);
+        }
+        // SAFETY: Data region bounds were checked against the mmap size above.
+        let region = unsafe {
+            std::slice::from_raw_parts(mmap.byte_add(data_offset) as *const u8, data_size)
+        };
+
+        // Pairs with the kernel's smp_wmb() after writing record data: every
+        // byte below head is visible once we observe head.
+        let head = data_head.load(Ordering::Acquire);
+        let mut tail = data_tail.load(Ordering::Relaxed);
+        let hdr_size = size_of::<perf_event_header>();
+        let mut records = 0;
+        while head - tail >= hdr_size as u64 {
+            let tail_mod = (tail % data_size as u64) as usize;
+            record_buf.resize(hdr_size, 0);
+            copy_from_ring(region, tail_mod, &mut record_buf[..hdr_size]);
+            // SAFETY: record_buf holds exactly size_of::<perf_event_header>() bytes.
+            let hdr: perf_event_header =
+                unsafe { std::ptr::read_unaligned(record_buf.as_ptr().cast()) };
+            let size = hdr.size as usize;
+            if size < hdr_size || size as u64 > head - tail {
+                // A record can't be smaller than its header or extend past
+                // head. Drop everything we have rather than read garbage.
+                error!(
+                    "Corrupt record of size {} with {} bytes available, skipping to head.",
+                    size,
+                    head - tail
+                );
+                tail = head;
+                break;
+            }
+            record_buf.resize(size, 0);
+            copy_from_ring(region, tail_mod, record_buf);
+            match Record::parse(&self.attr, record_buf) {
+                Ok(Record::Sample(sample)) => tracker.handle_sample(&sample),
+                Ok(Record::Lost(lost)) => tracker.handle_lost(&lost),
+                Ok(Record::Throttle(throttle)) => tracker.handle_throttle(&throttle, true),
+                Ok(Record::Unthrottle(throttle)) => tracker.handle_throttle(&throttle, false),
+                Ok(Record::Mmap2(mmap)) => tracker.handle_mmap(&mmap),
+                Ok(Record::Comm(comm)) => tracker.handle_comm(&comm),
+                Ok(Record::Fork(task)) => {
+                    tracker.handle_fork(&task);
+                    tasks.push(Record::Fork(task));
                 }
-            };
-            let mut events = Events::with_capacity(128);
-            let TOKEN = Token(0);
-            let mut poll = Poll::new().map_err(|_| PerfError::Poll)?;
+                Ok(Record::Exit(task)) => {
+                    tracker.handle_exit(&task);
+                    tasks.push(Record::Exit(task));
+                }
+                Ok(Record::Other(_)) => {}
+                Err(_) => error!("Failed to parse record of type {}.", hdr.type_),
+            }
+            tail += size as u64;
+            records += 1;
+        }
+        // Pairs with the kernel's smp_mb() before reading tail: our reads of
+        // the records complete before the space is handed back.
+        data_tail.store(tail, Ordering::Release);
+        Ok(records)
+    }
+}
+
+/// Copy dst.len() bytes out of the ring starting at offset, wrapping to the
+/// beginning of the ring if the copy runs past the end.
+fn copy_from_ring(region: &[u8], offset: usize, dst: &mut [u8]) {
+    let first = dst.len().min(region.len() - offset);
+    dst[..first].copy_from_slice(&region[offset..offset + first]);
+    let rest = dst.len() - first;
+    dst[first..].copy_from_slice(&region[..rest]);
+}
+
+type GroupOpener = Box<dyn FnMut(i32) -> Result<Vec<PerfEvent>, PerfError>>;
+
+/// New threads of `pid` get their own group opened by `open(tid)`.
+struct ThreadFollower {
+    pid: i32,
+    open: GroupOpener,
+}
+
+/// A set of sampling event groups polled from a single mio loop, e.g. one
+/// group per CPU or per thread. The first event of each group is the leader
+/// that owns the ring buffer, the rest redirect their output into it.
+/// Groups are indexed by their mio Token and dropped once their ring closes.
+#[derive(Default)]
+pub struct PerfEventSet {
+    groups: Vec<Option<Vec<PerfEvent>>>,
+    threads: HashMap<i32, usize>,
+    follower: Option<ThreadFollower>,
+    signals: Option<SignalFd>,
+    duration: Option<Duration>,
+}
+
+const SIGNAL_TOKEN: Token = Token(usize::MAX);
+
+/// Why PerfEventSet::sample_loop returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// Every ring buffer was closed, e.g. the traced tasks exited.
+    Closed,
+    /// The tracker reported it is finished.
+    Finished,
+    /// A stop signal was received.
+    Signal(i32),
+    /// The requested duration elapsed.
+    Timeout,
+}
+
+impl PerfEventSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_group(&mut self, group: Vec<PerfEvent>) -> Result<usize, PerfError> {
+        match group.first() {
+            Some(leader) if leader.mmap_hdr.is_some() => {
+                self.groups.push(Some(group));
+                Ok(self.groups.len() - 1)
+            }
+            _ => {
+                error!("Group leader has no perf buffer to read samples from.");
+                Err(PerfError::Mmap)
+            }
+        }
+    }
+
+    /// Open a group for every thread pid spawns while the sample loop runs
+    /// (seen as PERF_RECORD_FORK), and drop a thread's group when it exits.
+    /// Groups must have been opened with attr.task set for this to work.
+    /// Threads that already exist are picked up by scan_threads.
+    pub fn follow_threads(&mut self, pid: i32, open: GroupOpener) {
+        self.follower = Some(ThreadFollower { pid, open });
+    }
+
+    /// Open a group for every followed thread listed in /proc that has none
+    /// yet, and enable it if enable is set. Disabled groups emit no FORK
+    /// records, so this rescans until no new threads show up to catch the
+    /// ones spawned before their parent's group was enabled. Returns the
+    /// indices of the groups added.
+    pub fn scan_threads(&mut self, enable: bool) -> Result<Vec<usize>, PerfError> {
+        let Some(mut follower) = self.follower.take() else {
+            return Ok(Vec::new());
+        };
+        let result = self.scan_with(&mut follower, enable);
+        self.follower = Some(follower);
+        result
+    }
+
+    fn scan_with(
+        &mut self,
+        follower: &mut ThreadFollower,
+        enable: bool,
+    ) -> Result<Vec<usize>, PerfError> {
+        let mut tried = HashSet::new();
+        let mut added = Vec::new();
+        loop {
+            let tids = task_ids(follower.pid)?
+                .into_iter()
+                .filter(|tid| !self.threads.contains_key(tid) && tried.insert(*tid))
+                .collect::<Vec<_>>();
+            if tids.is_empty() {
+                return Ok(added);
+            }
+            for tid in tids {
+                // The thread may have exited in the meantime.
+                let Ok(group) = (follower.open)(tid) else {
+                    warn!("Failed to open events for thread {}.", tid);
+                    continue;
+                };
+                if enable {
+                    group[0].reset()?;
+                    group[0].enable()?;
+                }
+                let idx = self.add_group(group)?;
+                self.threads.insert(tid, idx);
+                added.push(idx);
+                debug!("Following thread {}.", tid);
+            }
+        }
+    }
+
+    /// Number of groups that are still open.
+    pub fn len(&self) -> usize {
+        self.groups.iter().flatten().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn enable(&self) -> Result<(), PerfError> {
+        self.groups.iter().flatten().try_for_each(|g| g[0].enable())
+    }
+
+    pub fn reset(&self) -> Result<(), PerfError> {
+        self.groups.iter().flatten().try_for_each(|g| g[0].reset())
+    }
+
+    /// Stop the sample loop when one of the signals handled by signals arrives.
+    pub fn stop_on_signals(&mut self, signals: SignalFd) {
+        self.signals = Some(signals);
+    }
+
+    /// Stop the sample loop once duration has passed since it started.
+    pub fn stop_after(&mut self, duration: Duration) {
+        self.duration = Some(duration);
+    }
+
+    /// Main event loop for reading samples from the perf sample buffers.
+    /// Every record is decoded and dispatched to the matching Tracker callback.
+    /// SAMPLE_RECORDs are decoded according to the leader's sample_type and
+    /// handed to the tracker as a `Sample`. Returns once every ring is closed,
+    /// the tracker reports it is finished, a stop signal arrives or the
+    /// duration is up.
+    pub fn sample_loop<T: super::Tracker>(
+        &mut self,
+        tracker: &mut T,
+    ) -> Result<StopReason, PerfError> {
+        if self.is_empty() {
+            error!("No perf buffer to read samples from.");
+            return Err(PerfError::Mmap);
+        }
+        let mut events = Events::with_capacity(128);
+        let mut poll = Poll::new().map_err(|_| PerfError::Poll)?;
+        for (i, group) in self.groups.iter().enumerate() {
+            if let Some(group) = group {
+                register(&poll, &group[0], i)?;
+            }
+        }
+        if let Some(signals) = self.signals.as_ref() {
             poll.registry()
                 .register(
-                    &mut SourceFd(&self.fd.as_raw_fd()),
-                    TOKEN,
+                    &mut SourceFd(&signals.as_raw_fd()),
+                    SIGNAL_TOKEN,
                     Interest::READABLE,
                 )
                 .map_err(|_| PerfError::Poll)?;
-            let mut overflows = 0;
-            let mut total_events_read = 0;
-            loop {
-                poll.poll(&mut events, None).map_err(|_| PerfError::Poll)?;
-                for event in events.iter() {
-                    overflows += 1;
-                    if event.token() == TOKEN && event.is_readable() {
-                        let mut events_read = 0;
-                        let mut record_buf: Vec<u8> = Vec::with_capacity(record_size);
-                        loop {
-                            // SAFETY: MMAP sample region bounds are valid and ring buffer is within bounds.
-                            // If the next sample record is larger than the difference between the
-                            // end of the buffer and the tail we read the record in two parts so
-                            // that we don't read past the end of the buffer.
-                            unsafe {
-                                let tail_mod = ((*mmap).data_tail % (*mmap).data_size) as usize;
-                                // Check that the next record is within the bounds of the ring.
-                                if record_size as u64 > (*mmap).data_head - (*mmap).data_tail {
-                                    //debug!("Overflows: {}", overflows);
-                                    //debug!("Events read: {}", events_read);
-                                    //debug!("Total events read: {}", total_events_read);
-                                    break;
-                                }
-                                // Check the remaining space in the ring buffer in case we need to
-                                // wrap.
-                                let rem = (*mmap).data_size as usize - tail_mod;
-                                let rem = if rem < record_size { rem } else { record_size };
-                                // record buf outlives ptr, and rem is always less than or equal to record_size.
-                                // all ptr arithmetic is done as byte count, rem is counting bytes.
-                                copy_nonoverlapping(
-                                    sample_region.byte_add(tail_mod) as *const u8,
-                                    record_buf.as_mut_ptr(),
-                                    rem,
-                                );
-                                if rem < record_size {
-                                    copy_nonoverlapping(
-                                        sample_region as *const u8,
-                                        record_buf.as_mut_ptr().byte_add(rem),
-                                        record_size - rem,
-                                    );
-                                }
-                                // Check the record type, we only care about SAMPLE_RECORDs for
-                                // now.
-                                // SAFETY: record_buf ptr is pointing to data that always has
-                                // size_of::<perf_event_header>() bytes at the beginning.
-                                let hdr: *const perf_event_header = record_buf.as_ptr().cast();
-                                if (*hdr).type_ == perf_event_type_PERF_RECORD_SAMPLE {
-                                    // Invoke caller provided function with ptr to raw bytes.
-                                    // Asking the caller to make an implicit assumption
-                                    // about the size and type of the record is not ideal.
-                                    // TODO: Consider a more type safe approach.
-                                    tracker.handle_sample(record_buf.as_ptr());
-                                }
-                                events_read += 1;
-                                total_events_read += 1;
-                                (*mmap).data_tail += record_size as u64;
-                            }
-                        }
-                        tracker.execute();
-                    } else if event.is_read_closed() {
-                        info!("Read closed.");
-                        return Ok(());
+        }
+        let deadline = self.duration.map(|d| Instant::now() + d);
+        let mut record_buf: Vec<u8> = Vec::new();
+        let mut tasks: Vec<Record> = Vec::new();
+        loop {
+            let timeout = deadline.map(|d| d.saturating_duration_since(Instant::now()));
+            match poll.poll(&mut events, timeout) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(_) => return Err(PerfError::Poll),
+            }
+            let mut signal = None;
+            for event in events.iter() {
+                if event.token() == SIGNAL_TOKEN {
+                    if let Some(signals) = self.signals.as_ref() {
+                        signal = signals.read().map_err(|_| PerfError::Poll)?;
                     }
+                    continue;
+                }
+                let Token(i) = event.token();
+                let Some(Some(group)) = self.groups.get(i) else {
+                    continue;
+                };
+                // A closed ring may still hold records, e.g. the EXIT record.
+                if event.is_readable() || event.is_read_closed() {
+                    group[0].drain(&mut record_buf, tracker, &mut tasks)?;
+                }
+                if event.is_read_closed() {
+                    debug!("Read closed on perf buffer {}.", i);
+                    self.close(&poll, i)?;
                 }
             }
-        } else {
-            error!("No perf buffer to read samples from.");
-            Err(PerfError::Mmap)
+            for task in tasks.drain(..) {
+                self.follow(&poll, &task)?;
+            }
+            let reason = if let Some(sig) = signal {
+                info!("Received signal {}, stopping.", sig);
+                Some(StopReason::Signal(sig))
+            } else if deadline.is_some_and(|d| Instant::now() >= d) {
+                info!("Duration elapsed, stopping.");
+                Some(StopReason::Timeout)
+            } else if tracker.finished() {
+                info!("Tracker finished.");
+                Some(StopReason::Finished)
+            } else if self.is_empty() {
+                info!("Read closed.");
+                Some(StopReason::Closed)
+            } else {
+                None
+            };
+            if let Some(reason) = reason {
+                // Pick up whatever is still buffered before handing back.
+                for group in self.groups.iter().flatten() {
+                    group[0].drain(&mut record_buf, tracker, &mut tasks)?;
+                }
+                return Ok(reason);
+            }
         }
     }
+
+    fn close(&mut self, poll: &Poll, i: usize) -> Result<(), PerfError> {
+        if let Some(group) = self.groups[i].take() {
+            poll.registry()
+                .deregister(&mut SourceFd(&group[0].fd.as_raw_fd()))
+                .map_err(|_| PerfError::Poll)?;
+            self.threads.retain(|_, idx| *idx != i);
+        }
+        Ok(())
+    }
+
+    fn follow(&mut self, poll: &Poll, task: &Record) -> Result<(), PerfError> {
+        let Some(follower) = self.follower.as_mut() else {
+            return Ok(());
+        };
+        match task {
+            Record::Fork(task) if task.pid as i32 == follower.pid => {
+                if self.threads.contains_key(&(task.tid as i32)) {
+                    return Ok(());
+                }
+                // The new thread may have spawned threads of its own before
+                // its group was enabled. The target may already be gone.
+                for idx in self.scan_threads(true).unwrap_or_default() {
+                    register(poll, &self.groups[idx].as_ref().unwrap()[0], idx)?;
+                }
+            }
+            Record::Exit(task) if task.pid as i32 == follower.pid => {
+                if let Some(&idx) = self.threads.get(&(task.tid as i32)) {
+                    debug!("Thread {} exited.", task.tid);
+                    self.close(poll, idx)?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+fn register(poll: &Poll, leader: &PerfEvent, i: usize) -> Result<(), PerfError> {
+    poll.registry()
+        .register(
+            &mut SourceFd(&leader.fd.as_raw_fd()),
+            Token(i),
+            Interest::READABLE,
+        )
+        .map_err(|_| PerfError::Poll)
+}
+
+/// Thread ids of pid as listed in /proc/<pid>/task.
+pub fn task_ids(pid: i32) -> Result<Vec<i32>, PerfError> {
+    let dir = std::fs::read_dir(format!("/proc/{}/task", pid)).map_err(|_| {
+        error!("Failed to list threads of {}.", pid);
+        PerfError::Io
+    })?;
+    Ok(dir
+        .flatten()
+        .filter_map(|entry| entry.file_name().to_str()?.parse().ok())
+        .collect())
+}
+
+/// Online CPUs as listed in /sys/devices/system/cpu/online.
+pub fn online_cpus() -> Result<Vec<i32>, PerfError> {
+    let list = std::fs::read_to_string("/sys/devices/system/cpu/online").map_err(|_| {
+        error!("Failed to read online cpus.");
+        PerfError::Io
+    })?;
+    parse_cpu_list(&list).ok_or_else(|| {
+        error!("Failed to parse online cpu list: {}", list.trim());
+        PerfError::Parse
+    })
+}
+
+/// Parse a kernel cpu/node list such as "0-3,8,10-11".
+pub fn parse_cpu_list(list: &str) -> Option<Vec<i32>> {
+    let mut cpus = Vec::new();
+    for range in list.trim().split(',').filter(|r| !r.is_empty()) {
+        match range.split_once('-') {
+            Some((lo, hi)) => cpus.extend(lo.parse::<i32>().ok()?..=hi.parse::<i32>().ok()?),
+            None => cpus.push(range.parse().ok()?),
+        }
+    }
+    Some(cpus)
 }
 
 impl Drop for PerfEvent {
@@ -275,8 +1160,11 @@ unsafe fn mmap_perf_buffer(
     let mmap_size = page_size * num_pages;
     debug!("Mmap size: {:#02x}", mmap_size);
     // MMAP region must be 1 + 2^n pages. First for header page and then ring buffer.
-    if ((num_pages - 1) & (num_pages - 2)) != 0 {
-        error!("Number of pages must be 1 + 2^n.");
+    if !(num_pages - 1).is_power_of_two() {
+        error!(
+            "Ring buffer must be a power of two pages, got {}.",
+            num_pages - 1
+        );
         return Err(PerfError::Mmap);
     }
     // SAFETY: Caller is responsible for ensuring that the file descriptor is valid.
@@ -295,3 +1183,238 @@ unsafe fn mmap_perf_buffer(
         ptr => Ok((ptr as *mut perf_event_mmap_page, mmap_size)),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A record of the given type with its perf_event_header.
+    fn record(type_: u32, misc: u16, payload: &[u8]) -> Vec<u8> {
+        let size = (size_of::<perf_event_header>() + payload.len()) as u16;
+        let mut buf = Vec::new();
+        buf.extend(type_.to_ne_bytes());
+        buf.extend(misc.to_ne_bytes());
+        buf.extend(size.to_ne_bytes());
+        buf.extend(payload);
+        buf
+    }
+
+    fn attr(sample_type: u32) -> perf_event_attr {
+        perf_event_attr {
+            sample_type: sample_type as u64,
+            ..Default::default()
+        }
+    }
+
+    fn u64s(values: &[u64]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_ne_bytes()).collect()
+    }
+
+    #[test]
+    fn sample_field_order() {
+        let attr = attr(
+            perf_event_sample_format_PERF_SAMPLE_IP
+                | perf_event_sample_format_PERF_SAMPLE_TID
+                | perf_event_sample_format_PERF_SAMPLE_TIME
+                | perf_event_sample_format_PERF_SAMPLE_ADDR
+                | perf_event_sample_format_PERF_SAMPLE_ID
+                | perf_event_sample_format_PERF_SAMPLE_CPU
+                | perf_event_sample_format_PERF_SAMPLE_PERIOD
+                | perf_event_sample_format_PERF_SAMPLE_DATA_SRC
+                | perf_event_sample_format_PERF_SAMPLE_PHYS_ADDR,
+        );
+        let mut payload = u64s(&[0x401000]);
+        payload.extend(10u32.to_ne_bytes());
+        payload.extend(11u32.to_ne_bytes());
+        payload.extend(u64s(&[1234, 0x7f00_0000_1000, 42]));
+        payload.extend(3u32.to_ne_bytes());
+        payload.extend(0u32.to_ne_bytes());
+        payload.extend(u64s(&[4000, 0, 0x1_2345_6000]));
+        let rec = record(perf_event_type_PERF_RECORD_SAMPLE, 2, &payload);
+        let s = Sample::parse(&attr, &rec).unwrap();
+        assert_eq!(s.misc, 2);
+        assert_eq!(s.ip, Some(0x401000));
+        assert_eq!((s.pid, s.tid), (Some(10), Some(11)));
+        assert_eq!(s.time, Some(1234));
+        assert_eq!(s.addr, Some(0x7f00_0000_1000));
+        assert_eq!(s.id, Some(42));
+        assert_eq!(s.cpu, Some(3));
+        assert_eq!(s.period, Some(4000));
+        assert!(s.data_src.is_some());
+        assert_eq!(s.phys_addr, Some(0x1_2345_6000));
+        assert_eq!(s.weight.map(|w| w.latency()), None);
+    }
+
+    #[test]
+    fn sample_raw_padding() {
+        let attr = attr(
+            perf_event_sample_format_PERF_SAMPLE_RAW | perf_event_sample_format_PERF_SAMPLE_WEIGHT,
+        );
+        // The size covers the padding that aligns size and data to 8 bytes.
+        let mut payload = 12u32.to_ne_bytes().to_vec();
+        payload.extend([1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 0, 0]);
+        payload.extend(u64s(&[77]));
+        let rec = record(perf_event_type_PERF_RECORD_SAMPLE, 0, &payload);
+        let s = Sample::parse(&attr, &rec).unwrap();
+        assert_eq!(s.raw.unwrap().len(), 12);
+        assert_eq!(s.weight.map(|w| w.latency()), Some(77));
+    }
+
+    #[test]
+    fn sample_stack_user_dyn_size() {
+        let attr = attr(
+            perf_event_sample_format_PERF_SAMPLE_STACK_USER
+                | perf_event_sample_format_PERF_SAMPLE_WEIGHT_STRUCT,
+        );
+        let mut payload = u64s(&[16]);
+        payload.extend([0xaa; 16]);
+        payload.extend(u64s(&[8]));
+        payload.extend(300u32.to_ne_bytes());
+        payload.extend(7u16.to_ne_bytes());
+        payload.extend(9u16.to_ne_bytes());
+        let rec = record(perf_event_type_PERF_RECORD_SAMPLE, 0, &payload);
+        let s = Sample::parse(&attr, &rec).unwrap();
+        assert_eq!(s.stack_user.unwrap(), vec![0xaa; 8]);
+        match s.weight {
+            Some(Weight::Struct {
+                var1_dw,
+                var2_w,
+                var3_w,
+            }) => assert_eq!((var1_dw, var2_w, var3_w), (300, 7, 9)),
+            other => panic!("expected a weight struct, got {:?}", other),
+        }
+        assert_eq!(s.weight.unwrap().latency(), 300);
+
+        // An empty stack has no dyn_size.
+        let mut payload = u64s(&[0]);
+        payload.extend(u64s(&[5]));
+        let rec = record(perf_event_type_PERF_RECORD_SAMPLE, 0, &payload);
+        let s = Sample::parse(&attr, &rec).unwrap();
+        assert_eq!(s.stack_user.unwrap(), Vec::<u8>::new());
+        assert_eq!(s.weight.unwrap().latency(), 5);
+    }
+
+    #[test]
+    fn sample_truncated() {
+        let addr = attr(
+            perf_event_sample_format_PERF_SAMPLE_ADDR
+                | perf_event_sample_format_PERF_SAMPLE_DATA_SRC,
+        );
+        let rec = record(perf_event_type_PERF_RECORD_SAMPLE, 0, &u64s(&[1]));
+        assert!(matches!(Sample::parse(&addr, &rec), Err(PerfError::Parse)));
+        // A stack size beyond the end of the record.
+        let stack = attr(perf_event_sample_format_PERF_SAMPLE_STACK_USER);
+        let rec = record(perf_event_type_PERF_RECORD_SAMPLE, 0, &u64s(&[64, 0]));
+        assert!(matches!(Sample::parse(&stack, &rec), Err(PerfError::Parse)));
+        // Not even a header.
+        assert!(matches!(
+            Sample::parse(&stack, &[0; 4]),
+            Err(PerfError::Parse)
+        ));
+        let rec = record(perf_event_type_PERF_RECORD_LOST, 0, &u64s(&[1, 2]));
+        assert!(matches!(Sample::parse(&stack, &rec), Err(PerfError::Parse)));
+    }
+
+    #[test]
+    fn record_types() {
+        let attr = attr(perf_event_sample_format_PERF_SAMPLE_ADDR);
+        let rec = record(perf_event_type_PERF_RECORD_LOST, 0, &u64s(&[5, 17]));
+        assert!(matches!(
+            Record::parse(&attr, &rec),
+            Ok(Record::Lost(Lost { id: 5, lost: 17 }))
+        ));
+
+        let mut payload = Vec::new();
+        payload.extend(100u32.to_ne_bytes());
+        payload.extend(101u32.to_ne_bytes());
+        payload.extend(u64s(&[0x7f00_0000_0000, 0x20_0000, 0]));
+        payload.extend(8u32.to_ne_bytes());
+        payload.extend(1u32.to_ne_bytes());
+        payload.extend(u64s(&[1234, 1]));
+        payload.extend(3u32.to_ne_bytes());
+        payload.extend(2u32.to_ne_bytes());
+        payload.extend(b"/lib/libc.so\0\0\0\0");
+        // sample_id_all trailer, ignored.
+        payload.extend(u64s(&[99]));
+        let rec = record(perf_event_type_PERF_RECORD_MMAP2, 0, &payload);
+        let Ok(Record::Mmap2(mmap)) = Record::parse(&attr, &rec) else {
+            panic!("expected an mmap2 record");
+        };
+        assert_eq!((mmap.pid, mmap.tid), (100, 101));
+        assert_eq!((mmap.addr, mmap.len), (0x7f00_0000_0000, 0x20_0000));
+        assert_eq!((mmap.maj, mmap.min, mmap.ino), (8, 1, 1234));
+        assert_eq!((mmap.prot, mmap.flags), (3, 2));
+        assert_eq!(mmap.filename, "/lib/libc.so");
+
+        let mut payload = Vec::new();
+        for v in [10u32, 1, 11, 10] {
+            payload.extend(v.to_ne_bytes());
+        }
+        payload.extend(u64s(&[555]));
+        let rec = record(perf_event_type_PERF_RECORD_FORK, 0, &payload);
+        let Ok(Record::Fork(task)) = Record::parse(&attr, &rec) else {
+            panic!("expected a fork record");
+        };
+        assert_eq!((task.pid, task.ppid, task.tid, task.ptid), (10, 1, 11, 10));
+        assert_eq!(task.time, 555);
+
+        let rec = record(perf_event_type_PERF_RECORD_SAMPLE, 0, &u64s(&[0x1000]));
+        let Ok(Record::Sample(s)) = Record::parse(&attr, &rec) else {
+            panic!("expected a sample record");
+        };
+        assert_eq!(s.addr, Some(0x1000));
+
+        let rec = record(perf_event_type_PERF_RECORD_SWITCH, 0, &[]);
+        assert!(matches!(
+            Record::parse(&attr, &rec),
+            Ok(Record::Other(perf_event_type_PERF_RECORD_SWITCH))
+        ));
+
+        // Unterminated and truncated strings.
+        let mut payload = 1u32.to_ne_bytes().to_vec();
+        payload.extend(1u32.to_ne_bytes());
+        payload.extend(b"abcd");
+        let rec = record(perf_event_type_PERF_RECORD_COMM, 0, &payload);
+        assert!(matches!(Record::parse(&attr, &rec), Err(PerfError::Parse)));
+        payload.extend(b"\0");
+        let rec = record(perf_event_type_PERF_RECORD_COMM, 0, &payload);
+        assert!(matches!(Record::parse(&attr, &rec), Err(PerfError::Parse)));
+    }
+
+    #[test]
+    fn data_src() {
+        let raw = (PERF_MEM_OP_LOAD as u64)
+            | (PERF_MEM_LVLNUM_RAM as u64) << PERF_MEM_LVLNUM_SHIFT
+            | (PERF_MEM_REMOTE_REMOTE as u64) << PERF_MEM_REMOTE_SHIFT
+            | 2 << 43;
+        let d = DataSrc::from_raw(raw);
+        assert_eq!(d.mem_op as u32, PERF_MEM_OP_LOAD);
+        assert_eq!(d.mem_lvl_num as u32, PERF_MEM_LVLNUM_RAM);
+        assert!(d.mem_remote);
+        assert_eq!(d.mem_hops, 2);
+        assert_eq!(d.source(), MemSource::RemoteDram);
+
+        let local = DataSrc::from_raw((PERF_MEM_LVLNUM_RAM as u64) << PERF_MEM_LVLNUM_SHIFT);
+        assert_eq!(local.source(), MemSource::LocalDram);
+        let cxl = DataSrc::from_raw((PERF_MEM_LVLNUM_CXL as u64) << PERF_MEM_LVLNUM_SHIFT);
+        assert_eq!(cxl.source(), MemSource::Cxl);
+
+        // No lvl_num, classified by the older mem_lvl bits.
+        let na = (PERF_MEM_LVLNUM_NA as u64) << PERF_MEM_LVLNUM_SHIFT;
+        let l3 = DataSrc::from_raw(na | (PERF_MEM_LVL_L3 as u64) << PERF_MEM_LVL_SHIFT);
+        assert_eq!(l3.source(), MemSource::L3);
+        let rem = DataSrc::from_raw(na | (PERF_MEM_LVL_REM_RAM1 as u64) << PERF_MEM_LVL_SHIFT);
+        assert_eq!(rem.source(), MemSource::RemoteDram);
+        assert_eq!(DataSrc::from_raw(na).source(), MemSource::Unknown);
+    }
+
+    #[test]
+    fn cpu_list() {
+        assert_eq!(
+            parse_cpu_list("0-3,8,10-11\n"),
+            Some(vec![0, 1, 2, 3, 8, 10, 11])
+        );
+        assert_eq!(parse_cpu_list("5"), Some(vec![5]));
+        assert_eq!(parse_cpu_list("x"), None);
+    }
+}
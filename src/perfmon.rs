@@ -0,0 +1,236 @@
+use std::{collections::HashMap, path::Path};
+
+use log::{debug, error};
+
+use serde_json::Value;
+
+use crate::{event::EventSpec, perf::PerfError};
+
+/// MSR_PEBS_LD_LAT_THRESHOLD, the load latency threshold goes in config1.
+const MSR_PEBS_LD_LAT: u64 = 0x3f6;
+
+/// A core PMU event from an intel/perfmon event file, e.g.
+/// https://github.com/intel/perfmon/blob/main/SKX/events/skylakex_core.json
+#[derive(Debug, Clone)]
+pub struct PerfmonEvent {
+    pub name: String,
+    pub config: u64,
+    pub config1: u64,
+    /// 0: no PEBS, 1: PEBS capable, 2: PEBS only.
+    pub pebs: u8,
+    /// The event records the data linear address.
+    pub data_la: bool,
+}
+
+impl PerfmonEvent {
+    fn from_json(event: &Value) -> Option<Self> {
+        let name = event.get("EventName")?.as_str()?.to_string();
+        let field = |key: &str| event.get(key).and_then(parse_num).unwrap_or(0);
+        // Some uncore/offcore events list two codes, the first is the one to use.
+        let code = event
+            .get("EventCode")?
+            .as_str()?
+            .split(',')
+            .next()
+            .and_then(parse_num_str)?;
+        let config = code
+            | (field("UMask") & 0xff) << 8
+            | (field("EdgeDetect") & 1) << 18
+            | (field("AnyThread") & 1) << 21
+            | (field("Invert") & 1) << 23
+            | (field("CounterMask") & 0xff) << 24
+            | field("UMaskExt") << 32;
+        let config1 = match (field("MSRIndex"), field("MSRValue")) {
+            (MSR_PEBS_LD_LAT, ldlat) => ldlat,
+            _ => 0,
+        };
+        Some(Self {
+            name,
+            config,
+            config1,
+            pebs: field("PEBS") as u8,
+            data_la: field("Data_LA") != 0,
+        })
+    }
+
+    pub fn precise(&self) -> bool {
+        self.pebs != 0
+    }
+}
+
+/// Event definitions loaded from an intel/perfmon JSON file, looked up by
+/// their case insensitive EventName.
+#[derive(Debug, Default)]
+pub struct Perfmon {
+    events: HashMap<String, PerfmonEvent>,
+}
+
+impl Perfmon {
+    pub fn load(path: &Path) -> Result<Self, PerfError> {
+        let text = std::fs::read_to_string(path).map_err(|_| {
+            error!("Failed to read perfmon file {}.", path.display());
+            PerfError::Io
+        })?;
+        let perfmon = Self::parse(&text)?;
+        debug!(
+            "Loaded {} perfmon events from {}.",
+            perfmon.events.len(),
+            path.display()
+        );
+        Ok(perfmon)
+    }
+
+    /// Accepts both the current {"Header": .., "Events": [..]} layout and
+    /// the older bare array of events.
+    pub fn parse(text: &str) -> Result<Self, PerfError> {
+        let json = serde_json::from_str::<Value>(text).map_err(|e| {
+            error!("Failed to parse perfmon json: {}", e);
+            PerfError::Parse
+        })?;
+        let Some(list) = json
+            .get("Events")
+            .and_then(Value::as_array)
+            .or_else(|| json.as_array())
+        else {
+            error!("Perfmon json has no event list.");
+            return Err(PerfError::Parse);
+        };
+        let events = list
+            .iter()
+            .filter_map(PerfmonEvent::from_json)
+            .map(|e| (e.name.to_ascii_uppercase(), e))
+            .collect();
+        Ok(Self { events })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&PerfmonEvent> {
+        self.events.get(&name.to_ascii_uppercase())
+    }
+
+    /// Resolve name into an event spec. tracem always samples with
+    /// precise_ip, so events without PEBS support are refused here rather
+    /// than failing in perf_event_open.
+    pub fn resolve(&self, name: &str) -> Result<Option<EventSpec>, PerfError> {
+        let Some(event) = self.get(name) else {
+            return Ok(None);
+        };
+        if !event.precise() {
+            error!("{} does not support precise sampling (PEBS).", event.name);
+            return Err(PerfError::Unsupported);
+        }
+        if !event.data_la {
+            debug!("{} does not record data addresses.", event.name);
+        }
+        let mut spec = EventSpec::raw(&event.name, event.config);
+        spec.config1 = event.config1;
+        Ok(Some(spec))
+    }
+}
+
+fn parse_num(v: &Value) -> Option<u64> {
+    match v {
+        Value::String(s) => parse_num_str(s),
+        Value::Number(n) => n.as_u64(),
+        _ => None,
+    }
+}
+
+fn parse_num_str(s: &str) -> Option<u64> {
+    let s = s.trim();
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EVENTS: &str = r#"[
+        {
+            "EventCode": "0xd1",
+            "UMask": "0x20",
+            "EventName": "MEM_LOAD_RETIRED.L3_MISS",
+            "BriefDescription": "Retired load instructions missed L3 cache as data sources \"quoted\" \ud83d\ude00",
+            "PEBS": "1",
+            "Data_LA": "1"
+        },
+        {
+            "EventCode": "0xcd",
+            "UMask": "0x01",
+            "EventName": "MEM_TRANS_RETIRED.LOAD_LATENCY_GT_32",
+            "CounterMask": 0,
+            "MSRIndex": "0x3F6",
+            "MSRValue": "0x20",
+            "PEBS": "2",
+            "Data_LA": "1"
+        },
+        {
+            "EventCode": "0x3c,0x3d",
+            "UMask": 0,
+            "CounterMask": 3,
+            "Invert": "1",
+            "EventName": "CPU_CLK_UNHALTED.THREAD_P\u002dX",
+            "PEBS": 0
+        }
+    ]"#;
+
+    fn check(perfmon: &Perfmon) {
+        let l3 = perfmon.get("mem_load_retired.l3_miss").unwrap();
+        assert_eq!((l3.config, l3.config1), (0x20d1, 0));
+        assert!(l3.precise() && l3.data_la);
+        let ldlat = perfmon.get("MEM_TRANS_RETIRED.LOAD_LATENCY_GT_32").unwrap();
+        assert_eq!((ldlat.config, ldlat.config1, ldlat.pebs), (0x1cd, 0x20, 2));
+        // Escapes in names, numbers as JSON numbers, the first of two codes.
+        let cycles = perfmon.get("cpu_clk_unhalted.thread_p-x").unwrap();
+        assert_eq!(cycles.config, 0x3c | 1 << 23 | 3 << 24);
+        assert!(!cycles.precise());
+    }
+
+    #[test]
+    fn bare_array() {
+        let perfmon = Perfmon::parse(EVENTS).unwrap();
+        assert_eq!(perfmon.events.len(), 3);
+        check(&perfmon);
+    }
+
+    #[test]
+    fn header_and_events() {
+        let text = format!(
+            r#"{{"Header": {{"Copyright": "Intel", "Version": "1.23"}}, "Events": {}}}"#,
+            EVENTS
+        );
+        check(&Perfmon::parse(&text).unwrap());
+    }
+
+    #[test]
+    fn resolve() {
+        let perfmon = Perfmon::parse(EVENTS).unwrap();
+        let spec = perfmon
+            .resolve("mem_trans_retired.load_latency_gt_32")
+            .unwrap()
+            .unwrap();
+        assert_eq!((spec.config, spec.config1), (0x1cd, 0x20));
+        assert!(perfmon.resolve("cpu_clk_unhalted.thread_p-x").is_err());
+        assert!(perfmon.resolve("no.such_event").unwrap().is_none());
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(Perfmon::parse("{\"Header\": {}}").is_err());
+        assert!(Perfmon::parse("[{\"EventName\": ").is_err());
+        assert!(Perfmon::parse("").is_err());
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(parse_num_str(" 0X3F6 "), Some(0x3f6));
+        assert_eq!(parse_num_str("32"), Some(32));
+        assert_eq!(parse_num_str("0xzz"), None);
+        assert_eq!(parse_num(&Value::from(7)), Some(7));
+        assert_eq!(parse_num(&Value::from(-1)), None);
+        assert_eq!(parse_num(&Value::from(1.5)), None);
+        assert_eq!(parse_num(&Value::Null), None);
+    }
+}
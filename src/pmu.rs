@@ -0,0 +1,266 @@
+use std::{collections::HashMap, path::Path};
+
+use log::{debug, error};
+
+use crate::{event::EventSpec, perf::PerfError};
+
+const EVENT_SOURCE_DEVICES: &str = "/sys/bus/event_source/devices";
+
+/// Which perf_event_attr word a format field is encoded into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigWord {
+    Config,
+    Config1,
+    Config2,
+}
+
+/// A format field such as `config:0-7,21`, the value bits are scattered
+/// over the listed ranges low bits first.
+#[derive(Debug, Clone)]
+pub struct Format {
+    pub word: ConfigWord,
+    pub bits: Vec<(u32, u32)>,
+}
+
+impl Format {
+    pub fn parse(text: &str) -> Option<Self> {
+        let (word, ranges) = text.trim().split_once(':')?;
+        let word = match word {
+            "config" => ConfigWord::Config,
+            "config1" => ConfigWord::Config1,
+            "config2" => ConfigWord::Config2,
+            _ => return None,
+        };
+        let bits = ranges
+            .split(',')
+            .map(|r| match r.split_once('-') {
+                Some((lo, hi)) => Some((lo.parse().ok()?, hi.parse().ok()?)),
+                None => r.parse().ok().map(|b| (b, b)),
+            })
+            .collect::<Option<Vec<(u32, u32)>>>()?;
+        Some(Self { word, bits })
+    }
+
+    pub fn width(&self) -> u32 {
+        self.bits.iter().map(|(lo, hi)| hi - lo + 1).sum()
+    }
+
+    /// Spread value over the field bits, None if it does not fit.
+    pub fn encode(&self, value: u64) -> Option<u64> {
+        if self.width() < 64 && value >> self.width() != 0 {
+            return None;
+        }
+        let (mut out, mut shift) = (0, 0);
+        for (lo, hi) in &self.bits {
+            let len = hi - lo + 1;
+            let mask = if len >= 64 { !0 } else { (1 << len) - 1 };
+            out |= ((value >> shift) & mask) << lo;
+            shift += len;
+        }
+        Some(out)
+    }
+}
+
+/// A PMU registered under /sys/bus/event_source/devices.
+#[derive(Debug, Clone)]
+pub struct Pmu {
+    pub name: String,
+    pub type_: u32,
+    pub formats: HashMap<String, Format>,
+    /// Event aliases, e.g. mem-loads -> "event=0xcd,umask=0x1,ldlat=3".
+    pub events: HashMap<String, String>,
+}
+
+impl Pmu {
+    pub fn load(name: &str) -> Result<Self, PerfError> {
+        let dir = Path::new(EVENT_SOURCE_DEVICES).join(name);
+        let type_ = std::fs::read_to_string(dir.join("type"))
+            .ok()
+            .and_then(|t| t.trim().parse().ok())
+            .ok_or_else(|| {
+                error!("No PMU named {} in {}.", name, EVENT_SOURCE_DEVICES);
+                PerfError::UnknownEvent
+            })?;
+        let formats = read_dir_files(&dir.join("format"))
+            .into_iter()
+            .filter_map(|(name, text)| Some((name, Format::parse(&text)?)))
+            .collect();
+        // Skip the .scale, .unit and .snapshot companions of an event.
+        let events = read_dir_files(&dir.join("events"))
+            .into_iter()
+            .filter(|(name, _)| !name.contains('.'))
+            .map(|(name, text)| (name, text.trim().to_string()))
+            .collect();
+        Ok(Self {
+            name: name.to_string(),
+            type_,
+            formats,
+            events,
+        })
+    }
+
+    /// Build an event from comma separated terms, e.g. `mem-loads,ldlat=30`.
+    /// A bare name is an event alias or a format field set to 1, later terms
+    /// override the ones an alias expands to.
+    pub fn event(&self, terms: &str) -> Result<EventSpec, PerfError> {
+        let mut spec = EventSpec {
+            name: format!("{}/{}/", self.name, terms),
+            type_: self.type_,
+            config: 0,
+            config1: 0,
+            config2: 0,
+            ibs: self.name == "ibs_op",
+        };
+        let mut fields = Vec::new();
+        for term in terms.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+            match self.events.get(term) {
+                Some(alias) => fields.extend(alias.split(',').filter_map(parse_term)),
+                None => fields.push(parse_term(term).ok_or_else(|| {
+                    error!("Invalid term {} for PMU {}.", term, self.name);
+                    PerfError::UnknownEvent
+                })?),
+            }
+        }
+        for (key, value) in fields {
+            let (word, mask, bits) = match key {
+                "config" => (ConfigWord::Config, u64::MAX, value),
+                "config1" => (ConfigWord::Config1, u64::MAX, value),
+                "config2" => (ConfigWord::Config2, u64::MAX, value),
+                _ => {
+                    let Some(format) = self.formats.get(key) else {
+                        error!("PMU {} has no format field {}.", self.name, key);
+                        return Err(PerfError::UnknownEvent);
+                    };
+                    let Some(bits) = format.encode(value) else {
+                        error!("{}={:#x} does not fit PMU {}.", key, value, self.name);
+                        return Err(PerfError::UnknownEvent);
+                    };
+                    let mask = format.encode(u64::MAX >> (64 - format.width())).unwrap();
+                    (format.word, mask, bits)
+                }
+            };
+            // Clear the field first so overrides replace the alias value.
+            let config = match word {
+                ConfigWord::Config => &mut spec.config,
+                ConfigWord::Config1 => &mut spec.config1,
+                ConfigWord::Config2 => &mut spec.config2,
+            };
+            *config = (*config & !mask) | bits;
+        }
+        debug!(
+            "{}: type {} config {:#x} config1 {:#x} config2 {:#x}",
+            spec.name, spec.type_, spec.config, spec.config1, spec.config2
+        );
+        Ok(spec)
+    }
+}
+
+/// Parse a perf style `pmu/terms/` spec. Trailing precision modifiers such
+/// as `pp` are accepted, tracem always samples precisely.
+pub fn parse_event(spec: &str) -> Result<EventSpec, PerfError> {
+    let invalid = || {
+        error!("Invalid PMU event {}, expected pmu/terms/.", spec);
+        PerfError::UnknownEvent
+    };
+    let (pmu, rest) = spec.split_once('/').ok_or_else(invalid)?;
+    let (terms, modifiers) = rest.rsplit_once('/').ok_or_else(invalid)?;
+    if !modifiers.chars().all(|c| c == 'p' || c == 'u') {
+        return Err(invalid());
+    }
+    Pmu::load(pmu)?.event(terms)
+}
+
+// A `key=value` term, a bare key means 1. Values like `?` that perf fills in
+// at runtime are not supported.
+fn parse_term(term: &str) -> Option<(&str, u64)> {
+    match term.trim().split_once('=') {
+        Some((key, value)) => {
+            let value = value.trim();
+            let value = match value.strip_prefix("0x") {
+                Some(hex) => u64::from_str_radix(hex, 16).ok()?,
+                None => value.parse().ok()?,
+            };
+            Some((key.trim(), value))
+        }
+        None => Some((term.trim(), 1)),
+    }
+}
+
+fn read_dir_files(dir: &Path) -> Vec<(String, String)> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(|e| {
+            let e = e.ok()?;
+            let name = e.file_name().into_string().ok()?;
+            let text = std::fs::read_to_string(e.path()).ok()?;
+            Some((name, text))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_parse() {
+        let f = Format::parse("config:0-7,21\n").unwrap();
+        assert_eq!(f.word, ConfigWord::Config);
+        assert_eq!(f.bits, vec![(0, 7), (21, 21)]);
+        assert_eq!(f.width(), 9);
+        assert_eq!(
+            Format::parse("config1:0-15").unwrap().word,
+            ConfigWord::Config1
+        );
+        assert!(Format::parse("config3:0-7").is_none());
+        assert!(Format::parse("config:a-b").is_none());
+    }
+
+    #[test]
+    fn format_encode() {
+        let event = Format::parse("config:0-7").unwrap();
+        assert_eq!(event.encode(0xcd), Some(0xcd));
+        assert_eq!(event.encode(0x100), None);
+        // Scattered fields take the low bits first.
+        let split = Format::parse("config:0-7,32-35").unwrap();
+        assert_eq!(split.encode(0x1d1), Some(0x1_0000_00d1));
+        assert_eq!(split.encode(0x1000), None);
+        let full = Format::parse("config1:0-63").unwrap();
+        assert_eq!(full.encode(u64::MAX), Some(u64::MAX));
+    }
+
+    #[test]
+    fn event_terms() {
+        let pmu = Pmu {
+            name: "cpu".to_string(),
+            type_: 4,
+            formats: HashMap::from([
+                ("event".to_string(), Format::parse("config:0-7").unwrap()),
+                ("umask".to_string(), Format::parse("config:8-15").unwrap()),
+                ("ldlat".to_string(), Format::parse("config1:0-15").unwrap()),
+            ]),
+            events: HashMap::from([(
+                "mem-loads".to_string(),
+                "event=0xcd,umask=0x1,ldlat=3".to_string(),
+            )]),
+        };
+        let spec = pmu.event("mem-loads,ldlat=30").unwrap();
+        assert_eq!((spec.type_, spec.config, spec.config1), (4, 0x1cd, 30));
+        assert!(!spec.ibs);
+        let spec = pmu.event("event=0xd0,umask=0x82,config2=5").unwrap();
+        assert_eq!((spec.config, spec.config2), (0x82d0, 5));
+        assert!(pmu.event("cmask=1").is_err());
+        assert!(pmu.event("event=0x100").is_err());
+        assert!(pmu.event("event=?").is_err());
+    }
+
+    #[test]
+    fn terms() {
+        assert_eq!(parse_term("event=0xcd"), Some(("event", 0xcd)));
+        assert_eq!(parse_term(" ldlat = 30 "), Some(("ldlat", 30)));
+        assert_eq!(parse_term("edge"), Some(("edge", 1)));
+        assert_eq!(parse_term("event=zz"), None);
+    }
+}
@@ -0,0 +1,1039 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, RecvTimeoutError, Sender},
+        Arc, RwLock,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use clap::ValueEnum;
+use log::{debug, error, info};
+
+use crate::{
+    numa_sys,
+    topology::{node_meminfo, Tier, TierTopology},
+    CostT, NodeT, PageT, MOVE_PAGES_BATCH, PAGE_SIZE,
+};
+
+/// Tracked pages, their cost, placement and size in bytes.
+pub type PageMap = HashMap<PageT, (CostT, NodeT, u64)>;
+
+// Pages that failed to migrate this many times are left where they are.
+const MAX_MIGRATION_FAILS: i32 = 10;
+
+/// Free memory targets for a fast tier, in percent of its capacity. Hot
+/// pages are promoted while free memory stays above high, cold pages are
+/// demoted once it drops below low until it is back at high.
+#[derive(Clone, Copy)]
+pub struct Watermarks {
+    pub low: u64,
+    pub high: u64,
+}
+
+impl Watermarks {
+    /// Bytes that may be promoted into tier (positive) or that should be
+    /// demoted out of it (negative).
+    fn headroom(&self, tier: &Tier) -> i64 {
+        let mem = tier.meminfo();
+        let low = mem.total * self.low / 100;
+        let high = mem.total * self.high / 100;
+        if mem.free > high {
+            (mem.free - high) as i64
+        } else if mem.free < low {
+            -((high - mem.free) as i64)
+        } else {
+            0
+        }
+    }
+}
+
+/// How page costs decay so they follow the current working set.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum AgingMode {
+    /// Costs only ever grow.
+    None,
+    /// Halve every cost once per aging period.
+    Halve,
+    /// Exponential decay with the aging period as time constant.
+    Ewma,
+    /// Costs cover the last full aging period plus the current one.
+    Window,
+}
+
+/// Applies an AgingMode on the policy thread. Time is the PERF_SAMPLE_TIME
+/// of the latest sample, so aging follows the trace rather than wall time.
+pub struct Aging {
+    mode: AgingMode,
+    period: u64,
+    last: Option<u64>,
+    // Window mode: cost of each page at the last window boundary.
+    base: HashMap<PageT, CostT>,
+}
+
+impl Aging {
+    pub fn new(mode: AgingMode, period: Duration) -> Self {
+        Self {
+            mode,
+            period: (period.as_nanos() as u64).max(1),
+            last: None,
+            base: HashMap::new(),
+        }
+    }
+
+    pub fn apply(&mut self, now: u64, tracking: &RwLock<PageMap>) {
+        // No sample yet, the clock still reads 0 rather than the trace time.
+        if now == 0 {
+            return;
+        }
+        let Some(last) = self.last else {
+            self.last = Some(now);
+            return;
+        };
+        let elapsed = now.saturating_sub(last);
+        // EWMA steps at least a quarter period at a time so integer costs
+        // still decay.
+        let step = match self.mode {
+            AgingMode::None => return,
+            AgingMode::Ewma => self.period / 4,
+            AgingMode::Halve | AgingMode::Window => self.period,
+        };
+        if elapsed < step.max(1) {
+            return;
+        }
+        let mut entries = tracking.write().unwrap();
+        match self.mode {
+            AgingMode::Halve => {
+                let shift = (elapsed / self.period).min(63);
+                entries
+                    .values_mut()
+                    .for_each(|(cost, _, _)| *cost >>= shift);
+            }
+            AgingMode::Ewma => {
+                let decay = (-(elapsed as f64) / self.period as f64).exp();
+                entries
+                    .values_mut()
+                    .for_each(|(cost, _, _)| *cost = (*cost as f64 * decay) as CostT);
+            }
+            // A whole period went by since the last window, none of the
+            // costs fall into the one that just ended.
+            AgingMode::Window if elapsed >= self.period.saturating_mul(2) => {
+                entries.values_mut().for_each(|(cost, _, _)| *cost = 0);
+                self.base.clear();
+            }
+            AgingMode::Window => {
+                let mut base = HashMap::with_capacity(entries.len());
+                for (page, (cost, _, _)) in entries.iter_mut() {
+                    *cost -= self.base.get(page).copied().unwrap_or(0).min(*cost);
+                    base.insert(*page, *cost);
+                }
+                self.base = base;
+            }
+            AgingMode::None => {}
+        }
+        // Carry the part of elapsed past the last period boundary over, or
+        // each period would stretch by up to a policy interval.
+        self.last = Some(match self.mode {
+            AgingMode::Ewma => now,
+            _ => last + elapsed / self.period * self.period,
+        });
+    }
+}
+
+/// A migration strategy. Given a snapshot of the tracked pages and the tier
+/// topology it returns the pages to move and the node to move each to.
+pub trait MigrationPolicy: Send {
+    fn decide(&mut self, pages: &PageMap, topology: &TierTopology) -> Vec<(PageT, u32)>;
+}
+
+/// Built-in migration strategies, see --policy.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum PolicyKind {
+    /// Promote the hottest pages while the fast tier is above the high
+    /// watermark, demote the coldest once it drops below the low one.
+    Watermark,
+    /// Keep a 4:1 page count ratio between each tier and the next slower.
+    Ratio,
+    /// Place the hottest pages in the fastest tiers, filling each up to its
+    /// high watermark.
+    Topk,
+    /// Promote pages at or above --hot-threshold, demote pages below
+    /// --cold-threshold while the fast tier is under pressure.
+    Threshold,
+    /// Promote pages sampled since the last round, demote pages not sampled
+    /// for --lru-rounds rounds while the fast tier is under pressure. Needs
+    /// --aging none.
+    Lru,
+}
+
+pub struct WatermarkPolicy {
+    pub watermarks: Watermarks,
+}
+
+impl MigrationPolicy for WatermarkPolicy {
+    fn decide(&mut self, pages: &PageMap, topology: &TierTopology) -> Vec<(PageT, u32)> {
+        let mut moves = Vec::new();
+        for slow in 1..topology.tiers.len() {
+            let fast = slow - 1;
+            let headroom = self.watermarks.headroom(&topology.tiers[fast]);
+            // Promote the hottest pages of the slow tier, demote the coldest
+            // of the fast one.
+            let (from, to) = match headroom {
+                0 => continue,
+                h if h > 0 => (slow, fast),
+                _ => (fast, slow),
+            };
+            let promote = to == fast;
+            let ranked = tier_pages(pages, &topology.tiers[from])
+                .filter(|(_, cost, _)| !promote || *cost > 0)
+                .collect();
+            let budget = if promote {
+                Budget::Fill(headroom.unsigned_abs())
+            } else {
+                Budget::Free(headroom.unsigned_abs())
+            };
+            let selected = select_pages(ranked, budget, promote);
+            moves.extend(to_tier(selected, &topology.tiers[to]));
+        }
+        moves
+    }
+}
+
+pub struct RatioPolicy;
+
+impl MigrationPolicy for RatioPolicy {
+    fn decide(&mut self, pages: &PageMap, topology: &TierTopology) -> Vec<(PageT, u32)> {
+        let mut moves = Vec::new();
+        for slow in 1..topology.tiers.len() {
+            let fast = slow - 1;
+            // Sizes in base pages so huge pages weigh what they hold.
+            let tier_len = |tier: usize| {
+                tier_pages(pages, &topology.tiers[tier])
+                    .map(|(_, _, size)| size / PAGE_SIZE)
+                    .sum::<u64>()
+            };
+            let (fast_tier_len, slow_tier_len) = (tier_len(fast), tier_len(slow));
+            // * Threshold conditions check*
+            // Maintain ratio of 4:1 for the fast tier to the slow tier
+            // If fast tier is 4 times larger than slow tier, migrate pages from fast to slow
+            // If slow tier is more than 1/4th of fast tier, migrate pages from slow to fast
+            let (from, to, ratio) = match (fast_tier_len, slow_tier_len) {
+                (fast_len, slow_len) if fast_len > 4 * slow_len => {
+                    (fast, slow, fast_len / (4 * (1 + slow_len)))
+                }
+                (fast_len, slow_len) if slow_len > fast_len / 2 => {
+                    (slow, fast, slow_len / fast_len.div_ceil(2).max(1))
+                }
+                _ => continue,
+            };
+            let ranked = tier_pages(pages, &topology.tiers[from]).collect();
+            let budget = if to == fast {
+                Budget::Fill(ratio * PAGE_SIZE)
+            } else {
+                Budget::Free(ratio * PAGE_SIZE)
+            };
+            let selected = select_pages(ranked, budget, to == fast);
+            moves.extend(to_tier(selected, &topology.tiers[to]));
+        }
+        moves
+    }
+}
+
+pub struct TopKPolicy {
+    pub watermarks: Watermarks,
+}
+
+impl MigrationPolicy for TopKPolicy {
+    fn decide(&mut self, pages: &PageMap, topology: &TierTopology) -> Vec<(PageT, u32)> {
+        let mut ranked = pages
+            .iter()
+            .filter(|(_, entry)| migratable(entry))
+            .filter_map(|(page, (cost, node, size))| {
+                Some((*page, *cost, *size, topology.tier_of(node.0)?))
+            })
+            .collect::<Vec<_>>();
+        let mut moves = Vec::new();
+        let mut rest = &mut ranked[..];
+        for (i, tier) in topology.tiers.iter().enumerate() {
+            // A tier holds what it holds now plus its headroom, the slowest
+            // tier takes everything left.
+            let budget = if i + 1 == topology.tiers.len() {
+                u64::MAX
+            } else {
+                let held = rest
+                    .iter()
+                    .filter(|(_, _, _, t)| *t == i)
+                    .map(|(_, _, size, _)| size)
+                    .sum::<u64>() as i64;
+                (held + self.watermarks.headroom(tier)).max(0) as u64
+            };
+            let k = rank_within(rest, Budget::Fill(budget), true, |(_, cost, size, _)| {
+                (*cost, *size)
+            });
+            if k == 0 {
+                continue;
+            }
+            let (top, tail) = rest.split_at_mut(k);
+            let misplaced = top
+                .iter()
+                .filter(|(_, _, _, t)| *t != i)
+                .map(|(page, _, _, _)| *page)
+                .collect();
+            moves.extend(to_tier(misplaced, tier));
+            rest = tail;
+        }
+        moves
+    }
+}
+
+pub struct ThresholdPolicy {
+    pub hot: CostT,
+    pub cold: CostT,
+    pub watermarks: Watermarks,
+}
+
+impl MigrationPolicy for ThresholdPolicy {
+    fn decide(&mut self, pages: &PageMap, topology: &TierTopology) -> Vec<(PageT, u32)> {
+        let mut moves = Vec::new();
+        for slow in 1..topology.tiers.len() {
+            let fast = slow - 1;
+            let headroom = self.watermarks.headroom(&topology.tiers[fast]);
+            if headroom > 0 {
+                let hot = tier_pages(pages, &topology.tiers[slow])
+                    .filter(|(_, cost, _)| *cost >= self.hot)
+                    .collect();
+                let selected = select_pages(hot, Budget::Fill(headroom as u64), true);
+                moves.extend(to_tier(selected, &topology.tiers[fast]));
+            } else if headroom < 0 {
+                let cold = tier_pages(pages, &topology.tiers[fast])
+                    .filter(|(_, cost, _)| *cost < self.cold)
+                    .collect();
+                let selected = select_pages(cold, Budget::Free(headroom.unsigned_abs()), false);
+                moves.extend(to_tier(selected, &topology.tiers[slow]));
+            }
+        }
+        moves
+    }
+}
+
+/// Approximates LRU from the samples: a page whose cost grew since the last
+/// round was referenced, otherwise it ages by a round. Costs must only ever
+/// grow, see AgingMode::None.
+pub struct LruPolicy {
+    pub rounds: u32,
+    pub watermarks: Watermarks,
+    // Cost at the last round and rounds since the page was last referenced.
+    seen: HashMap<PageT, (CostT, u32)>,
+}
+
+impl LruPolicy {
+    pub fn new(rounds: u32, watermarks: Watermarks) -> Self {
+        Self {
+            rounds,
+            watermarks,
+            seen: HashMap::new(),
+        }
+    }
+}
+
+impl MigrationPolicy for LruPolicy {
+    fn decide(&mut self, pages: &PageMap, topology: &TierTopology) -> Vec<(PageT, u32)> {
+        let seen = pages
+            .iter()
+            .map(|(page, (cost, _, _))| {
+                let idle = match self.seen.get(page) {
+                    Some((last, idle)) if cost <= last => idle + 1,
+                    _ => 0,
+                };
+                (*page, (*cost, idle))
+            })
+            .collect::<HashMap<_, _>>();
+        self.seen = seen;
+        let idle = |page: &PageT| self.seen.get(page).map_or(0, |(_, idle)| *idle);
+        let mut moves = Vec::new();
+        for slow in 1..topology.tiers.len() {
+            let fast = slow - 1;
+            let headroom = self.watermarks.headroom(&topology.tiers[fast]);
+            if headroom > 0 {
+                let recent = tier_pages(pages, &topology.tiers[slow])
+                    .filter(|(page, _, _)| idle(page) == 0)
+                    .collect();
+                let selected = select_pages(recent, Budget::Fill(headroom as u64), true);
+                moves.extend(to_tier(selected, &topology.tiers[fast]));
+            } else if headroom < 0 {
+                // Rank by idle rounds, the least recently used go first.
+                let stale = tier_pages(pages, &topology.tiers[fast])
+                    .map(|(page, _, size)| (page, idle(&page) as CostT, size))
+                    .filter(|(_, idle, _)| *idle >= self.rounds as CostT)
+                    .collect();
+                let selected = select_pages(stale, Budget::Free(headroom.unsigned_abs()), true);
+                moves.extend(to_tier(selected, &topology.tiers[slow]));
+            }
+        }
+        moves
+    }
+}
+
+fn migratable((_, node, _): &(CostT, NodeT, u64)) -> bool {
+    node.1 > -MAX_MIGRATION_FAILS
+}
+
+// Migratable pages placed on tier, with their cost and size.
+fn tier_pages<'a>(
+    pages: &'a PageMap,
+    tier: &'a Tier,
+) -> impl Iterator<Item = (PageT, CostT, u64)> + 'a {
+    pages
+        .iter()
+        .filter(|(_, entry)| tier.contains(entry.1 .0) && migratable(entry))
+        .map(|(page, (cost, _, size))| (*page, *cost, *size))
+}
+
+// Send pages to the node of tier with the most free memory.
+fn to_tier(pages: Vec<PageT>, tier: &Tier) -> Vec<(PageT, u32)> {
+    let Some(node) = tier
+        .nodes
+        .iter()
+        .copied()
+        .max_by_key(|n| node_meminfo(*n).map_or(0, |m| m.free))
+    else {
+        return Vec::new();
+    };
+    pages.into_iter().map(|page| (page, node)).collect()
+}
+
+// How many bytes of pages rank_within picks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Budget {
+    // Pages moving into a tier must fit in it. A page that does not is
+    // skipped and smaller ones further down are taken instead.
+    Fill(u64),
+    // Pages moving out of a tier must free at least this much, the last
+    // page taken may overshoot it.
+    Free(u64),
+}
+
+// The hottest (or coldest) pages within budget, hottest (or coldest) first.
+fn select_pages(mut pages: Vec<(PageT, CostT, u64)>, budget: Budget, hottest: bool) -> Vec<PageT> {
+    let k = rank_within(&mut pages, budget, hottest, |(_, cost, size)| {
+        (*cost, *size)
+    });
+    pages.truncate(k);
+    pages.into_iter().map(|(page, _, _)| page).collect()
+}
+
+// Move the hottest (or coldest) pages within budget to the front, in ranked
+// order, and return how many that is. Every page is at least PAGE_SIZE, so
+// only the first budget / PAGE_SIZE need sorting and a partial sort keeps
+// this close to linear in the number of tracked pages. Filling sorts more in
+// growing chunks while pages are skipped and room is left.
+fn rank_within<T>(
+    pages: &mut [T],
+    budget: Budget,
+    hottest: bool,
+    key: impl Fn(&T) -> (CostT, u64),
+) -> usize {
+    let order = |a: &T, b: &T| {
+        if hottest {
+            key(b).0.cmp(&key(a).0)
+        } else {
+            key(a).0.cmp(&key(b).0)
+        }
+    };
+    // Sort the best n pages of pages[from..] to its front.
+    let rank = |pages: &mut [T], from: usize, n: u64| {
+        let rest = &mut pages[from..];
+        let n = usize::try_from(n).map_or(rest.len(), |n| n.min(rest.len()));
+        if n > 0 && n < rest.len() {
+            rest.select_nth_unstable_by(n - 1, order);
+        }
+        rest[..n].sort_unstable_by(order);
+        n
+    };
+    match budget {
+        Budget::Free(budget) => {
+            let k = rank(pages, 0, budget.div_ceil(PAGE_SIZE));
+            let mut freed = 0;
+            pages[..k]
+                .iter()
+                .take_while(|page| {
+                    let more = freed < budget;
+                    freed += key(page).1;
+                    more
+                })
+                .count()
+        }
+        Budget::Fill(budget) => {
+            let (mut taken, mut ranked, mut used) = (0, 0, 0);
+            while ranked < pages.len() && budget - used >= PAGE_SIZE {
+                let n = rank(
+                    pages,
+                    ranked,
+                    ((budget - used) / PAGE_SIZE).max(ranked as u64),
+                );
+                for i in ranked..ranked + n {
+                    let size = key(&pages[i]).1;
+                    if used + size <= budget {
+                        pages.swap(taken, i);
+                        taken += 1;
+                        used += size;
+                    }
+                }
+                ranked += n;
+            }
+            taken
+        }
+    }
+}
+
+/// Token buckets bounding migrations by bandwidth and by pages per policy
+/// round. Unused tokens carry over, up to one second of bandwidth and two
+/// rounds of pages.
+pub struct RateLimit {
+    bytes_per_sec: Option<u64>,
+    pages_per_round: Option<u64>,
+    bytes: f64,
+    pages: u64,
+    last: Option<Instant>,
+}
+
+impl RateLimit {
+    pub fn new(mb_per_sec: Option<u64>, pages_per_round: Option<u64>) -> Self {
+        Self {
+            bytes_per_sec: mb_per_sec.map(|mb| mb << 20),
+            pages_per_round,
+            bytes: 0.0,
+            pages: 0,
+            last: None,
+        }
+    }
+
+    // The bandwidth bucket holds at least the largest page to move, so a page
+    // bigger than a second of bandwidth still fits once the bucket is full.
+    fn refill(&mut self, largest: u64) {
+        let now = Instant::now();
+        if let Some(rate) = self.bytes_per_sec {
+            let elapsed = self
+                .last
+                .map_or(1.0, |last| now.duration_since(last).as_secs_f64());
+            let cap = rate.max(largest) as f64;
+            self.bytes = (self.bytes + rate as f64 * elapsed).min(cap);
+        }
+        if let Some(pages) = self.pages_per_round {
+            self.pages = (self.pages + pages).min(2 * pages);
+        }
+        self.last = Some(now);
+    }
+
+    /// Trim moves to what the buckets allow and take their tokens. A huge
+    /// page counts as one page but takes its full size in bandwidth. Moves
+    /// are admitted in the order the policy ranked them up to the first that
+    /// does not fit. The moves behind it wait too, so the tokens it needs
+    /// build up over rounds instead of going to smaller pages.
+    fn admit(&mut self, mut moves: Vec<(PageT, u32)>, pages: &PageMap) -> Vec<(PageT, u32)> {
+        let size = |page: &PageT| pages.get(page).map_or(PAGE_SIZE, |entry| entry.2);
+        self.refill(moves.iter().map(|(page, _)| size(page)).max().unwrap_or(0));
+        let decided = moves.len();
+        let limit_bytes = self.bytes_per_sec.is_some();
+        let limit_pages = self.pages_per_round.is_some();
+        let mut admitted = 0;
+        for (page, _) in &moves {
+            let size = size(page) as f64;
+            if (limit_pages && self.pages == 0) || (limit_bytes && size > self.bytes) {
+                break;
+            }
+            if limit_bytes {
+                self.bytes -= size;
+            }
+            if limit_pages {
+                self.pages -= 1;
+            }
+            admitted += 1;
+        }
+        moves.truncate(admitted);
+        if moves.len() < decided {
+            debug!(
+                "Rate limit allows {} of {} page moves.",
+                moves.len(),
+                decided
+            );
+        }
+        moves
+    }
+}
+
+/// Records the moves a policy decides on instead of making them, as CSV
+/// lines of time, page, from node, to node and cost. Time is the
+/// PERF_SAMPLE_TIME of the latest sample.
+pub struct DryRun {
+    log: Option<BufWriter<File>>,
+    rounds: u64,
+    decisions: u64,
+    // Distinct pages decided on and their size.
+    pages: HashMap<PageT, u64>,
+    // Decisions and their summed cost by (from, to) node.
+    moves: BTreeMap<(u32, u32), (u64, CostT)>,
+}
+
+impl DryRun {
+    pub fn new(log: Option<&Path>) -> std::io::Result<Self> {
+        let log = match log {
+            Some(path) => {
+                let mut log = BufWriter::new(File::create(path)?);
+                writeln!(log, "time,page,from,to,cost")?;
+                Some(log)
+            }
+            None => None,
+        };
+        Ok(Self {
+            log,
+            rounds: 0,
+            decisions: 0,
+            pages: HashMap::new(),
+            moves: BTreeMap::new(),
+        })
+    }
+
+    fn record(&mut self, time: u64, snapshot: &PageMap, moves: &[(PageT, u32)]) {
+        self.rounds += 1;
+        for (page, to) in moves {
+            let Some((cost, (from, _), size)) = snapshot.get(page) else {
+                continue;
+            };
+            if let Some(log) = self.log.as_mut() {
+                if writeln!(log, "{},{:#x},{},{},{}", time, page, from, to, cost).is_err() {
+                    error!("Failed to write the decision log.");
+                    self.log = None;
+                }
+            }
+            let entry = self.moves.entry((*from, *to)).or_default();
+            entry.0 += 1;
+            entry.1 += cost;
+            self.decisions += 1;
+            self.pages.insert(*page, *size);
+        }
+    }
+
+    fn report(&mut self) {
+        if let Some(log) = self.log.as_mut() {
+            if log.flush().is_err() {
+                error!("Failed to write the decision log.");
+            }
+        }
+        info!(
+            "Dry run: {} decisions over {} rounds, {} distinct pages ({} MiB).",
+            self.decisions,
+            self.rounds,
+            self.pages.len(),
+            self.pages.values().sum::<u64>() >> 20
+        );
+        for ((from, to), (n, cost)) in &self.moves {
+            info!(
+                "Would move node {} -> {}: {} pages, total cost {}.",
+                from, to, n, cost
+            );
+        }
+    }
+}
+
+pub struct Policy {
+    handle: Option<JoinHandle<()>>,
+    // Dropping or sending on it stops the policy thread.
+    stop: Option<Sender<()>>,
+    interval: Duration,
+    topology: Arc<TierTopology>,
+    aging: Option<Aging>,
+    strategy: Option<Box<dyn MigrationPolicy>>,
+    limit: Option<RateLimit>,
+    dry_run: Option<DryRun>,
+}
+
+impl Policy {
+    pub fn new(
+        interval: Duration,
+        topology: Arc<TierTopology>,
+        aging: Aging,
+        strategy: Box<dyn MigrationPolicy>,
+        limit: RateLimit,
+        dry_run: Option<DryRun>,
+    ) -> Self {
+        Self {
+            handle: None,
+            stop: None,
+            interval,
+            topology,
+            aging: Some(aging),
+            strategy: Some(strategy),
+            limit: Some(limit),
+            dry_run,
+        }
+    }
+
+    /// Run a policy round every interval until join, independently of how
+    /// often samples arrive.
+    pub fn start(
+        &mut self,
+        target_pid: i32,
+        tracking: Arc<RwLock<PageMap>>,
+        clock: Arc<AtomicU64>,
+    ) {
+        if self.handle.is_some() {
+            return;
+        }
+        let (Some(mut aging), Some(mut strategy), Some(mut limit)) =
+            (self.aging.take(), self.strategy.take(), self.limit.take())
+        else {
+            return;
+        };
+        let topology = self.topology.clone();
+        let mut dry_run = self.dry_run.take();
+        let interval = self.interval;
+        let (stop, stopped) = mpsc::channel();
+        self.stop = Some(stop);
+        self.handle = Some(thread::spawn(move || {
+            let mut next = Instant::now() + interval;
+            loop {
+                match stopped.recv_timeout(next.saturating_duration_since(Instant::now())) {
+                    Err(RecvTimeoutError::Timeout) => {
+                        let now = clock.load(Ordering::Relaxed);
+                        aging.apply(now, &tracking);
+                        Self::execute(
+                            target_pid,
+                            &tracking,
+                            &topology,
+                            strategy.as_mut(),
+                            &mut limit,
+                            dry_run.as_mut().map(|d| (d, now)),
+                        );
+                        // Skip the rounds a slow one overran instead of
+                        // running them back to back.
+                        next = (next + interval).max(Instant::now());
+                    }
+                    Ok(()) | Err(RecvTimeoutError::Disconnected) => {
+                        if let Some(dry_run) = dry_run.as_mut() {
+                            dry_run.report();
+                        }
+                        break;
+                    }
+                }
+            }
+        }))
+    }
+
+    pub fn join(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+        if let Some(handle) = self.handle.take() {
+            handle.join().unwrap();
+        }
+    }
+
+    // Query where pages currently live with move_pages in query mode (nodes
+    // NULL). Pages that are no longer mapped or were never faulted in are
+    // dropped from tracking. A target_pid of -1 means there is no single
+    // process to query.
+    pub fn refresh(target_pid: i32, tracking: &RwLock<PageMap>, pages: &[PageT]) {
+        if target_pid == -1 {
+            return;
+        }
+        let (mut placed, mut evicted) = (0, 0);
+        for batch in pages.chunks(MOVE_PAGES_BATCH) {
+            let mut batch = batch.to_vec();
+            let mut status = vec![-123; batch.len()];
+            let ret = unsafe {
+                numa_sys::move_pages(
+                    target_pid,
+                    batch.len() as u64,
+                    batch.as_mut_ptr().cast(),
+                    std::ptr::null(),
+                    status.as_mut_ptr(),
+                    0,
+                )
+            };
+            if ret < 0 {
+                error!("Failed to query page placement.");
+                return;
+            }
+            let mut entries = tracking.write().unwrap();
+            for (p, s) in batch.iter().zip(status.iter()) {
+                match *s {
+                    s if s >= 0 => {
+                        if let Some((_, node, _)) = entries.get_mut(p) {
+                            node.0 = s as u32;
+                            placed += 1;
+                        }
+                    }
+                    s if s == -libc::ENOENT || s == -libc::EFAULT => {
+                        entries.remove(p);
+                        evicted += 1;
+                    }
+                    _ => {}
+                }
+            }
+        }
+        if evicted > 0 {
+            debug!(
+                "Placed {} pages, evicted {} unmapped pages.",
+                placed, evicted
+            );
+        }
+    }
+
+    fn execute(
+        target_pid: i32,
+        tracking: &RwLock<PageMap>,
+        topology: &TierTopology,
+        strategy: &mut dyn MigrationPolicy,
+        limit: &mut RateLimit,
+        dry_run: Option<(&mut DryRun, u64)>,
+    ) {
+        if target_pid == -1 {
+            return;
+        }
+        // A dry run never calls move_pages, not even to query placement, and
+        // decides on the nodes --phys-addr samples placed pages on.
+        if dry_run.is_none() {
+            let pages = tracking.read().unwrap().keys().copied().collect::<Vec<_>>();
+            Self::refresh(target_pid, tracking, &pages);
+        }
+        let snapshot = tracking.read().unwrap().clone();
+        let moves = limit.admit(strategy.decide(&snapshot, topology), &snapshot);
+        match dry_run {
+            Some((dry_run, now)) => dry_run.record(now, &snapshot, &moves),
+            None => Self::migrate(target_pid, tracking, &moves),
+        }
+    }
+
+    fn migrate(target_pid: i32, tracking: &RwLock<PageMap>, moves: &[(PageT, u32)]) {
+        let (mut moved, mut failed) = (0, 0);
+        for batch in moves.chunks(MOVE_PAGES_BATCH) {
+            let mut pages = batch.iter().map(|(page, _)| *page).collect::<Vec<_>>();
+            let nodes = batch
+                .iter()
+                .map(|(_, node)| *node as i32)
+                .collect::<Vec<_>>();
+            let mut status = vec![-123; batch.len()];
+            let ret = unsafe {
+                numa_sys::move_pages(
+                    target_pid,
+                    pages.len() as u64,
+                    pages.as_mut_ptr().cast(),
+                    nodes.as_ptr(),
+                    status.as_mut_ptr(),
+                    0,
+                )
+            };
+            if ret < 0 {
+                error!("Failed to move pages.");
+                return;
+            }
+            let mut entries = tracking.write().unwrap();
+            for (p, s) in pages.iter().zip(status.iter()) {
+                if let Some((_, node, _)) = entries.get_mut(p) {
+                    // Check that status is non-negative
+                    if *s < 0 {
+                        node.1 -= 1;
+                        failed += 1;
+                    } else {
+                        node.0 = *s as u32;
+                        moved += 1;
+                    }
+                }
+            }
+        }
+        if !moves.is_empty() {
+            debug!("Moved {} pages, {} failed.", moved, failed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aging_waits_for_the_first_sample() {
+        let second = Duration::from_secs(1);
+        let tracking = RwLock::new(PageMap::from([(0, (64, (0, 0), PAGE_SIZE))]));
+        let mut aging = Aging::new(AgingMode::Halve, second);
+        aging.apply(0, &tracking);
+        // Boot relative sample time, a full period after the first sample.
+        let start = 5_000 * 1_000_000_000;
+        aging.apply(start, &tracking);
+        assert_eq!(tracking.read().unwrap()[&0].0, 64);
+        aging.apply(start + 1_000_000_000, &tracking);
+        assert_eq!(tracking.read().unwrap()[&0].0, 32);
+    }
+
+    #[test]
+    fn aging_keeps_period_boundaries() {
+        let (period, start) = (1_000_000_000, 5_000_000_000);
+        let tracking = RwLock::new(PageMap::from([(0, (64, (0, 0), PAGE_SIZE))]));
+        let cost = || tracking.read().unwrap()[&0].0;
+        let mut aging = Aging::new(AgingMode::Halve, Duration::from_nanos(period));
+        aging.apply(start, &tracking);
+        // Rounds land late, halving still happens once per period.
+        aging.apply(start + period * 3 / 2, &tracking);
+        assert_eq!(cost(), 32);
+        aging.apply(start + period * 2, &tracking);
+        assert_eq!(cost(), 16);
+        aging.apply(start + period * 5, &tracking);
+        assert_eq!(cost(), 2);
+
+        let mut aging = Aging::new(AgingMode::Window, Duration::from_nanos(period));
+        aging.apply(start, &tracking);
+        tracking.write().unwrap().get_mut(&0).unwrap().0 = 10;
+        aging.apply(start + period, &tracking);
+        assert_eq!(cost(), 10);
+        tracking.write().unwrap().get_mut(&0).unwrap().0 += 5;
+        aging.apply(start + period * 2, &tracking);
+        assert_eq!(cost(), 5);
+        // More than a window later nothing is left of the old samples.
+        tracking.write().unwrap().get_mut(&0).unwrap().0 += 7;
+        aging.apply(start + period * 4, &tracking);
+        assert_eq!(cost(), 0);
+        tracking.write().unwrap().get_mut(&0).unwrap().0 += 3;
+        aging.apply(start + period * 5, &tracking);
+        assert_eq!(cost(), 3);
+    }
+
+    #[test]
+    fn ratio_with_empty_fast_tier() {
+        let tier = |node| Tier {
+            nodes: vec![node],
+            capacity: 0,
+        };
+        let topology = TierTopology {
+            tiers: vec![tier(0), tier(1)],
+        };
+        let pages = (0..4)
+            .map(|i| (i * PAGE_SIZE, (i, (1, 0), PAGE_SIZE)))
+            .collect::<PageMap>();
+        let mut moves = RatioPolicy.decide(&pages, &topology);
+        moves.sort();
+        assert_eq!(
+            moves,
+            vec![
+                (0, 0),
+                (PAGE_SIZE, 0),
+                (2 * PAGE_SIZE, 0),
+                (3 * PAGE_SIZE, 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn rate_limit_waits_for_pages_that_do_not_fit() {
+        let huge = 1 << 30;
+        let pages = PageMap::from([
+            (0, (1, (1, 0), PAGE_SIZE)),
+            (huge, (1, (1, 0), huge)),
+            (2 * huge, (1, (1, 0), PAGE_SIZE)),
+        ]);
+        let moves = vec![(0, 0), (huge, 0), (2 * huge, 0)];
+        let mut limit = RateLimit::new(Some(1), None);
+        // The page behind the huge one does not take the tokens it waits for.
+        assert_eq!(limit.admit(moves.clone(), &pages), vec![(0, 0)]);
+        assert_eq!(limit.bytes, ((1 << 20) - PAGE_SIZE) as f64);
+        // The bucket may grow to the huge page.
+        limit.bytes = huge as f64;
+        limit.last = Some(Instant::now());
+        let first = vec![(huge, 0), (0, 0)];
+        assert_eq!(limit.admit(first, &pages), vec![(huge, 0)]);
+
+        let mut limit = RateLimit::new(None, Some(2));
+        assert_eq!(limit.admit(moves.clone(), &pages), vec![(0, 0), (huge, 0)]);
+        assert_eq!(limit.admit(moves, &pages), vec![(0, 0), (huge, 0)]);
+        assert_eq!(limit.pages, 0);
+    }
+
+    #[test]
+    fn rank_within_base_pages() {
+        let mut pages = (0..10u64)
+            .map(|i| (i * PAGE_SIZE, i, PAGE_SIZE))
+            .collect::<Vec<_>>();
+        let key = |(_, c, s): &(u64, u64, u64)| (*c, *s);
+        let k = rank_within(&mut pages, Budget::Fill(3 * PAGE_SIZE), true, key);
+        assert_eq!(k, 3);
+        assert_eq!(
+            pages[..k].iter().map(|p| p.1).collect::<Vec<_>>(),
+            vec![9, 8, 7]
+        );
+        let k = rank_within(&mut pages, Budget::Fill(2 * PAGE_SIZE + 1), false, key);
+        assert_eq!(k, 2);
+        assert_eq!(
+            pages[..k].iter().map(|p| p.1).collect::<Vec<_>>(),
+            vec![0, 1]
+        );
+        let k = rank_within(&mut pages, Budget::Free(2 * PAGE_SIZE + 1), false, key);
+        assert_eq!(
+            pages[..k].iter().map(|p| p.1).collect::<Vec<_>>(),
+            vec![0, 1, 2]
+        );
+        assert_eq!(
+            rank_within(&mut pages, Budget::Fill(PAGE_SIZE - 1), true, key),
+            0
+        );
+        assert_eq!(rank_within(&mut pages, Budget::Free(0), true, key), 0);
+        assert_eq!(
+            rank_within(&mut pages, Budget::Fill(u64::MAX), true, key),
+            10
+        );
+    }
+
+    #[test]
+    fn rank_within_huge_pages() {
+        let huge = 2 << 20;
+        let mut pages = vec![
+            (0, 5, PAGE_SIZE),
+            (huge, 100, huge),
+            (2 * huge, 50, PAGE_SIZE),
+            (3 * huge, 10, PAGE_SIZE),
+            (4 * huge, 1, huge),
+        ];
+        let key = |(_, c, s): &(u64, u64, u64)| (*c, *s);
+        // The hottest page does not fit, the next ones still do.
+        let k = rank_within(&mut pages, Budget::Fill(huge - 1), true, key);
+        assert_eq!(
+            pages[..k].iter().map(|p| p.1).collect::<Vec<_>>(),
+            vec![50, 10, 5]
+        );
+        let k = rank_within(&mut pages, Budget::Fill(huge + PAGE_SIZE), true, key);
+        assert_eq!(
+            pages[..k].iter().map(|p| p.1).collect::<Vec<_>>(),
+            vec![100, 50]
+        );
+        // The coldest page frees more than asked for.
+        let k = rank_within(&mut pages, Budget::Free(PAGE_SIZE), false, key);
+        assert_eq!(pages[..k].iter().map(|p| p.1).collect::<Vec<_>>(), vec![1]);
+        let k = rank_within(&mut pages, Budget::Free(huge + 1), false, key);
+        assert_eq!(
+            pages[..k].iter().map(|p| p.1).collect::<Vec<_>>(),
+            vec![1, 5]
+        );
+    }
+
+    #[test]
+    fn select_pages_budget() {
+        let pages = vec![
+            (0, 1, PAGE_SIZE),
+            (PAGE_SIZE, 3, PAGE_SIZE),
+            (2 * PAGE_SIZE, 2, PAGE_SIZE),
+        ];
+        assert_eq!(
+            select_pages(pages.clone(), Budget::Fill(2 * PAGE_SIZE), true),
+            vec![PAGE_SIZE, 2 * PAGE_SIZE]
+        );
+        assert_eq!(
+            select_pages(pages.clone(), Budget::Free(PAGE_SIZE), false),
+            vec![0]
+        );
+        assert!(select_pages(pages, Budget::Fill(0), true).is_empty());
+    }
+}
@@ -0,0 +1,72 @@
+use std::{
+    fs::File,
+    io::{self, Read},
+    mem::{size_of, zeroed},
+    os::fd::{AsRawFd, FromRawFd, RawFd},
+};
+
+use log::debug;
+
+/// Delivers signals as readable data on a file descriptor so they can be
+/// handled from a mio loop. The signals are blocked for the calling thread and
+/// every thread it spawns afterwards, so create this before starting threads.
+pub struct SignalFd {
+    fd: File,
+}
+
+impl SignalFd {
+    pub fn new(signals: &[i32]) -> io::Result<Self> {
+        // SAFETY: mask is initialized by sigemptyset before use and every
+        // return value is checked.
+        unsafe {
+            let mut mask: libc::sigset_t = zeroed();
+            libc::sigemptyset(&mut mask);
+            for sig in signals {
+                if libc::sigaddset(&mut mask, *sig) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            let ret = libc::pthread_sigmask(libc::SIG_BLOCK, &mask, std::ptr::null_mut());
+            if ret != 0 {
+                return Err(io::Error::from_raw_os_error(ret));
+            }
+            let fd = libc::signalfd(-1, &mask, libc::SFD_NONBLOCK | libc::SFD_CLOEXEC);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            debug!("Handling signals {:?} on fd {}.", signals, fd);
+            Ok(Self {
+                fd: File::from_raw_fd(fd),
+            })
+        }
+    }
+
+    /// Another handle on the same signalfd.
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Ok(Self {
+            fd: self.fd.try_clone()?,
+        })
+    }
+
+    /// Next pending signal number, if any.
+    pub fn read(&self) -> io::Result<Option<i32>> {
+        let mut buf = [0u8; size_of::<libc::signalfd_siginfo>()];
+        match (&self.fd).read(&mut buf) {
+            Ok(n) if n == buf.len() => {
+                // SAFETY: The kernel wrote a whole signalfd_siginfo.
+                let info: libc::signalfd_siginfo =
+                    unsafe { std::ptr::read_unaligned(buf.as_ptr().cast()) };
+                Ok(Some(info.ssi_signo as i32))
+            }
+            Ok(_) => Ok(None),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl AsRawFd for SignalFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
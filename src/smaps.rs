@@ -0,0 +1,170 @@
+use std::collections::BTreeMap;
+
+use log::{debug, error};
+
+use crate::{perf::PerfError, PageT, PAGE_SIZE};
+
+/// The page size of each hugetlbfs mapping of a process, read from
+/// /proc/<pid>/smaps. Transparent huge pages come and go with khugepaged and
+/// splits, so their size is taken from each sample instead, see
+/// PERF_SAMPLE_DATA_PAGE_SIZE. Addresses outside them are in base pages.
+#[derive(Debug, Default)]
+pub struct PageSizes {
+    // Mapping start to its end and page size.
+    maps: BTreeMap<u64, (u64, u64)>,
+}
+
+// The smaps fields of one mapping that tell its page size, in bytes.
+// hugetlbfs reports its page size as KernelPageSize, THP mappings report the
+// base page size there.
+#[derive(Default)]
+struct Mapping {
+    start: u64,
+    end: u64,
+    kernel_page_size: u64,
+}
+
+impl PageSizes {
+    pub fn load(pid: i32) -> Result<Self, PerfError> {
+        let path = format!("/proc/{}/smaps", pid);
+        let smaps = std::fs::read_to_string(&path).map_err(|_| {
+            error!("Failed to read {}.", path);
+            PerfError::Io
+        })?;
+        let sizes = Self::parse(&smaps);
+        debug!("{} huge page mappings in {}.", sizes.maps.len(), path);
+        Ok(sizes)
+    }
+
+    /// Parse smaps text. Each mapping starts with a `start-end perms ...`
+    /// line followed by `Field: value kB` lines.
+    pub fn parse(smaps: &str) -> Self {
+        let mut mappings = Vec::new();
+        for line in smaps.lines() {
+            let range = line
+                .split_whitespace()
+                .next()
+                .and_then(|r| r.split_once('-'))
+                .and_then(|(start, end)| {
+                    Some((
+                        u64::from_str_radix(start, 16).ok()?,
+                        u64::from_str_radix(end, 16).ok()?,
+                    ))
+                });
+            if let Some((start, end)) = range {
+                mappings.push(Mapping {
+                    start,
+                    end,
+                    ..Default::default()
+                });
+                continue;
+            }
+            let (Some(mapping), Some(value)) =
+                (mappings.last_mut(), line.strip_prefix("KernelPageSize:"))
+            else {
+                continue;
+            };
+            if let Some(bytes) = value
+                .trim()
+                .strip_suffix("kB")
+                .and_then(|v| v.trim().parse::<u64>().ok())
+            {
+                mapping.kernel_page_size = bytes * 1024;
+            }
+        }
+        Self {
+            maps: mappings
+                .into_iter()
+                .filter(|m| m.kernel_page_size > PAGE_SIZE && m.kernel_page_size.is_power_of_two())
+                .map(|m| (m.start, (m.end, m.kernel_page_size)))
+                .collect(),
+        }
+    }
+
+    /// The page addr falls in and its size. Only aligned ranges that lie
+    /// fully inside a mapping are huge pages, the rest is in base pages.
+    pub fn page_of(&self, addr: u64) -> (PageT, u64) {
+        if let Some((start, (end, size))) = self.maps.range(..=addr).next_back() {
+            let page = addr & !(size - 1);
+            if page >= *start && page + size <= *end {
+                return (page, *size);
+            }
+        }
+        (addr & !(PAGE_SIZE - 1), PAGE_SIZE)
+    }
+
+    /// The page of addr given the size the kernel sampled it in, falling
+    /// back to the hugetlbfs mappings when the sample has none.
+    pub fn sampled_page(&self, addr: u64, size: Option<u64>) -> (PageT, u64) {
+        match size.filter(|s| *s >= PAGE_SIZE && s.is_power_of_two()) {
+            Some(size) => (addr & !(size - 1), size),
+            None => self.page_of(addr),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SMAPS: &str = "\
+55550000-55560000 r-xp 00000000 08:01 3                  /bin/x
+Size:                 64 kB
+KernelPageSize:        4 kB
+Rss:                  64 kB
+AnonHugePages:         0 kB
+VmFlags: rd ex mr mw me
+7f0000000000-7f0000600000 rw-p 00000000 00:00 0
+Size:               6144 kB
+KernelPageSize:        4 kB
+Rss:                6144 kB
+AnonHugePages:      4096 kB
+VmFlags: rd wr mr mw me ac
+7f1000000000-7f1080000000 rw-s 00000000 00:10 5         /dev/hugepages/x
+KernelPageSize:  1048576 kB
+Rss:                   0 kB
+";
+
+    #[test]
+    fn parse() {
+        let sizes = PageSizes::parse(SMAPS);
+        // THP mappings are left to the sampled page size.
+        assert_eq!(sizes.maps.len(), 1);
+        assert_eq!(sizes.page_of(0x55551234), (0x55551000, PAGE_SIZE));
+        assert_eq!(sizes.page_of(0x7f0000234567), (0x7f0000234000, PAGE_SIZE));
+        assert_eq!(sizes.page_of(0x7f1040000005), (0x7f1040000000, 1 << 30));
+        // Past the end of a huge mapping.
+        assert_eq!(sizes.page_of(0x7f1080000005), (0x7f1080000000, PAGE_SIZE));
+    }
+
+    #[test]
+    fn page_of_unaligned_mapping() {
+        let smaps = "\
+7f0000100000-7f0000500000 rw-p 00000000 00:00 0
+KernelPageSize:     2048 kB
+";
+        let sizes = PageSizes::parse(smaps);
+        // The head and tail are too short for a huge page.
+        assert_eq!(sizes.page_of(0x7f0000100010), (0x7f0000100000, PAGE_SIZE));
+        assert_eq!(sizes.page_of(0x7f0000200010), (0x7f0000200000, 2 << 20));
+        assert_eq!(sizes.page_of(0x7f0000400010), (0x7f0000400000, PAGE_SIZE));
+    }
+
+    #[test]
+    fn sampled_page() {
+        let sizes = PageSizes::parse(SMAPS);
+        assert_eq!(
+            sizes.sampled_page(0x7f0000234567, Some(2 << 20)),
+            (0x7f0000200000, 2 << 20)
+        );
+        assert_eq!(
+            sizes.sampled_page(0x7f0000234567, Some(PAGE_SIZE)),
+            (0x7f0000234000, PAGE_SIZE)
+        );
+        // No size, as for IBS on older kernels.
+        assert_eq!(
+            sizes.sampled_page(0x7f1040000005, Some(0)),
+            (0x7f1040000000, 1 << 30)
+        );
+    }
+}
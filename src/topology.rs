@@ -0,0 +1,296 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use log::{debug, error};
+
+use crate::perf::{parse_cpu_list, PerfError};
+
+const NODE_DIR: &str = "/sys/devices/system/node";
+const MEMORY_DIR: &str = "/sys/devices/system/memory";
+
+/// Which NUMA node each hotpluggable memory block belongs to, used to find
+/// the node of a physical address.
+#[derive(Debug, Default)]
+pub struct MemoryBlocks {
+    block_size: u64,
+    nodes: HashMap<u64, u32>,
+}
+
+impl MemoryBlocks {
+    /// Read block_size_bytes and the memoryN links under each nodeN.
+    pub fn load() -> Result<Self, PerfError> {
+        let block_size = std::fs::read_to_string(Path::new(MEMORY_DIR).join("block_size_bytes"))
+            .ok()
+            .and_then(|s| u64::from_str_radix(s.trim(), 16).ok())
+            .filter(|size| *size > 0)
+            .ok_or_else(|| {
+                error!("Failed to read {}/block_size_bytes.", MEMORY_DIR);
+                PerfError::Io
+            })?;
+        let mut nodes = HashMap::new();
+        for (node, dir) in node_dirs()? {
+            let Ok(entries) = std::fs::read_dir(&dir) else {
+                continue;
+            };
+            for entry in entries.filter_map(|e| e.ok()) {
+                let name = entry.file_name();
+                if let Some(block) = name
+                    .to_str()
+                    .and_then(|n| n.strip_prefix("memory"))
+                    .and_then(|n| n.parse::<u64>().ok())
+                {
+                    nodes.insert(block, node);
+                }
+            }
+        }
+        debug!(
+            "Loaded {} memory blocks of {:#x} bytes.",
+            nodes.len(),
+            block_size
+        );
+        Ok(Self { block_size, nodes })
+    }
+
+    pub fn node_of(&self, phys_addr: u64) -> Option<u32> {
+        self.nodes.get(&(phys_addr / self.block_size)).copied()
+    }
+}
+
+/// The nodeN directories and their node ids.
+pub fn node_dirs() -> Result<Vec<(u32, PathBuf)>, PerfError> {
+    let entries = std::fs::read_dir(NODE_DIR).map_err(|_| {
+        error!("Failed to read {}.", NODE_DIR);
+        PerfError::Io
+    })?;
+    let mut nodes = entries
+        .filter_map(|e| {
+            let e = e.ok()?;
+            let node = e.file_name().to_str()?.strip_prefix("node")?.parse().ok()?;
+            Some((node, e.path()))
+        })
+        .collect::<Vec<_>>();
+    nodes.sort();
+    Ok(nodes)
+}
+
+const MEMORY_TIERING_DIR: &str = "/sys/devices/virtual/memory_tiering";
+
+/// MemTotal and MemFree of a node in bytes.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NodeMemInfo {
+    pub total: u64,
+    pub free: u64,
+}
+
+impl NodeMemInfo {
+    /// Parse nodeN/meminfo, lines look like "Node 0 MemFree:  3407984 kB".
+    fn parse(text: &str) -> Option<Self> {
+        let mut info = Self::default();
+        for line in text.lines() {
+            let mut words = line.split_whitespace().skip(2);
+            let field = match words.next() {
+                Some("MemTotal:") => &mut info.total,
+                Some("MemFree:") => &mut info.free,
+                _ => continue,
+            };
+            *field = words.next()?.parse::<u64>().ok()? * 1024;
+        }
+        Some(info)
+    }
+}
+
+pub fn node_meminfo(node: u32) -> Option<NodeMemInfo> {
+    let text = std::fs::read_to_string(format!("{}/node{}/meminfo", NODE_DIR, node)).ok()?;
+    NodeMemInfo::parse(&text)
+}
+
+/// A set of nodes with similar performance and their total capacity.
+#[derive(Debug, Clone)]
+pub struct Tier {
+    pub nodes: Vec<u32>,
+    pub capacity: u64,
+}
+
+impl Tier {
+    fn new(nodes: Vec<u32>) -> Self {
+        let capacity = nodes
+            .iter()
+            .filter_map(|n| node_meminfo(*n))
+            .map(|m| m.total)
+            .sum();
+        Self { nodes, capacity }
+    }
+
+    pub fn contains(&self, node: u32) -> bool {
+        self.nodes.contains(&node)
+    }
+
+    /// Current MemTotal and MemFree summed over the tier.
+    pub fn meminfo(&self) -> NodeMemInfo {
+        self.nodes
+            .iter()
+            .filter_map(|n| node_meminfo(*n))
+            .fold(NodeMemInfo::default(), |acc, m| NodeMemInfo {
+                total: acc.total + m.total,
+                free: acc.free + m.free,
+            })
+    }
+}
+
+/// Memory tiers ordered from fastest to slowest.
+#[derive(Debug, Clone)]
+pub struct TierTopology {
+    pub tiers: Vec<Tier>,
+}
+
+impl TierTopology {
+    /// Use the user tier file if given, otherwise the kernel memory tiers,
+    /// otherwise split cpu nodes from memory only nodes (CXL, PMEM), and as a
+    /// last resort give each node a tier of its own in node id order.
+    pub fn load(path: Option<&Path>) -> Result<Self, PerfError> {
+        let topology = match path {
+            Some(path) => {
+                let text = std::fs::read_to_string(path).map_err(|_| {
+                    error!("Failed to read tier file {}.", path.display());
+                    PerfError::Io
+                })?;
+                Self::parse(&text).ok_or_else(|| {
+                    error!("Invalid tier file {}.", path.display());
+                    PerfError::Parse
+                })?
+            }
+            None => Self::from_memory_tiering()
+                .filter(|t| t.tiers.len() > 1)
+                .or_else(|| Self::from_node_cpus().filter(|t| t.tiers.len() > 1))
+                .map_or_else(Self::per_node, Ok)?,
+        };
+        for (i, tier) in topology.tiers.iter().enumerate() {
+            debug!(
+                "Tier {}: nodes {:?}, {} MiB.",
+                i,
+                tier.nodes,
+                tier.capacity >> 20
+            );
+        }
+        Ok(topology)
+    }
+
+    /// One tier per line, fastest first, as a node list like `0-1` or `2,3`.
+    /// Empty lines and `#` comments are skipped.
+    pub fn parse(text: &str) -> Option<Self> {
+        let tiers = text
+            .lines()
+            .map(|l| l.split('#').next().unwrap().trim())
+            .filter(|l| !l.is_empty())
+            .map(parse_node_list)
+            .collect::<Option<Vec<_>>>()?;
+        (!tiers.is_empty()).then(|| Self {
+            tiers: tiers.into_iter().map(Tier::new).collect(),
+        })
+    }
+
+    /// memory_tierN directories, a lower N means a lower abstract distance
+    /// and so a faster tier.
+    pub fn from_memory_tiering() -> Option<Self> {
+        let mut tiers = std::fs::read_dir(MEMORY_TIERING_DIR)
+            .ok()?
+            .filter_map(|e| {
+                let e = e.ok()?;
+                let id = e
+                    .file_name()
+                    .to_str()?
+                    .strip_prefix("memory_tier")?
+                    .parse::<u32>()
+                    .ok()?;
+                let nodes = std::fs::read_to_string(e.path().join("nodelist")).ok()?;
+                Some((id, parse_node_list(&nodes)?))
+            })
+            .filter(|(_, nodes)| !nodes.is_empty())
+            .collect::<Vec<_>>();
+        tiers.sort();
+        Some(Self {
+            tiers: tiers.into_iter().map(|(_, n)| Tier::new(n)).collect(),
+        })
+    }
+
+    /// Nodes with cpus are the fast tier, memory only nodes the slow one.
+    pub fn from_node_cpus() -> Option<Self> {
+        let read = |name: &str| {
+            std::fs::read_to_string(Path::new(NODE_DIR).join(name))
+                .ok()
+                .and_then(|l| parse_node_list(&l))
+        };
+        let (cpu, memory) = (read("has_cpu")?, read("has_memory")?);
+        let slow = memory
+            .iter()
+            .copied()
+            .filter(|n| !cpu.contains(n))
+            .collect::<Vec<_>>();
+        let fast = memory.into_iter().filter(|n| cpu.contains(n)).collect();
+        Some(Self {
+            tiers: [fast, slow]
+                .into_iter()
+                .filter(|t: &Vec<u32>| !t.is_empty())
+                .map(Tier::new)
+                .collect(),
+        })
+    }
+
+    pub fn per_node() -> Result<Self, PerfError> {
+        Ok(Self {
+            tiers: node_dirs()?
+                .into_iter()
+                .map(|(node, _)| Tier::new(vec![node]))
+                .collect(),
+        })
+    }
+
+    pub fn tier_of(&self, node: u32) -> Option<usize> {
+        self.tiers.iter().position(|t| t.contains(node))
+    }
+}
+
+fn parse_node_list(list: &str) -> Option<Vec<u32>> {
+    parse_cpu_list(list)?
+        .into_iter()
+        .map(|n| u32::try_from(n).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_tiers() {
+        let topology = TierTopology::parse("# fast\n0-1\n\n2,4 # cxl\n  3\n").unwrap();
+        let nodes = topology
+            .tiers
+            .iter()
+            .map(|t| t.nodes.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(nodes, vec![vec![0, 1], vec![2, 4], vec![3]]);
+        assert_eq!(topology.tier_of(4), Some(1));
+        assert_eq!(topology.tier_of(5), None);
+        assert!(TierTopology::parse("0\nx\n").is_none());
+        assert!(TierTopology::parse("0-1,-2\n").is_none());
+        assert!(TierTopology::parse("# nothing\n\n").is_none());
+    }
+
+    #[test]
+    fn parse_meminfo() {
+        let text = "\
+Node 1 MemTotal:       16384000 kB
+Node 1 MemFree:         3407984 kB
+Node 1 MemUsed:        12976016 kB
+Node 1 HugePages_Total:     0
+";
+        let info = NodeMemInfo::parse(text).unwrap();
+        assert_eq!(info.total, 16384000 * 1024);
+        assert_eq!(info.free, 3407984 * 1024);
+        assert!(NodeMemInfo::parse("Node 1 MemFree: lots kB\n").is_none());
+        assert_eq!(NodeMemInfo::parse("").unwrap().total, 0);
+    }
+}